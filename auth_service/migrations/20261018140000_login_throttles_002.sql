@@ -0,0 +1,14 @@
+-- Failed logins per username and per client IP, shared by every auth_service instance
+CREATE TABLE login_throttles (
+    kind TEXT NOT NULL CHECK (kind IN ('username', 'ip')),
+    key TEXT NOT NULL,
+    failures INTEGER NOT NULL DEFAULT 0,
+    last_failure_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    -- No logins for this key until then, NULL when it isn't locked
+    locked_until TIMESTAMPTZ,
+
+    PRIMARY KEY (kind, key)
+);
+
+CREATE INDEX idx_login_throttles_last_failure_at ON login_throttles(last_failure_at);
@@ -0,0 +1,2 @@
+-- Usernames keep the case they were registered with, but Alice and alice are the same person
+CREATE UNIQUE INDEX idx_users_username_lower ON users(LOWER(username));
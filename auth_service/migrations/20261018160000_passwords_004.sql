@@ -0,0 +1,17 @@
+-- Bumped whenever every existing token should stop working (password changed or reset)
+ALTER TABLE users ADD COLUMN session_version INTEGER NOT NULL DEFAULT 0;
+
+-- One-time codes for getting back into an account without the password
+CREATE TABLE recovery_codes (
+    id BIGSERIAL PRIMARY KEY,
+    user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    -- SHA-256, the codes are random enough that a slow hash isn't needed
+    code_hash TEXT NOT NULL,
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+
+    UNIQUE(user_id, code_hash)
+);
+
+CREATE INDEX idx_recovery_codes_user_id ON recovery_codes(user_id);
@@ -0,0 +1,6 @@
+-- TOTP two-factor, the secret is set by setup and only enforced once a first code confirms it
+ALTER TABLE users ADD COLUMN totp_secret BYTEA;
+ALTER TABLE users ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Last time step a code was accepted for, so the same code can't be used twice
+ALTER TABLE users ADD COLUMN totp_last_step BIGINT;
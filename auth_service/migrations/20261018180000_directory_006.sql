@@ -0,0 +1,2 @@
+-- Prefix search on usernames, LIKE 'abc%' can only use an index with text_pattern_ops
+CREATE INDEX idx_users_username_lower_pattern ON users (LOWER(username) text_pattern_ops);
@@ -0,0 +1,12 @@
+-- Optional profile fields, NULL until the user sets them
+ALTER TABLE users
+    ADD COLUMN display_name TEXT,
+    ADD COLUMN bio TEXT;
+
+-- Kept out of users so selecting a user doesn't drag the image along
+CREATE TABLE user_avatars (
+    user_id UUID PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    content_type TEXT NOT NULL,
+    data BYTEA NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
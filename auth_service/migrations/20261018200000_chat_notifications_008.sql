@@ -0,0 +1,13 @@
+-- Things socket_server has to hear about even if it's down at the time, written in the same transaction
+-- as the change itself and deleted once socket_server has acknowledged them
+CREATE TABLE chat_notifications (
+    id BIGSERIAL PRIMARY KEY,
+    kind TEXT NOT NULL CHECK (kind IN ('user_deleted')),
+    -- No foreign key, for deletions the user is already gone
+    user_id UUID NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_chat_notifications_next_attempt_at ON chat_notifications(next_attempt_at);
@@ -0,0 +1,4 @@
+-- What the avatar URL is versioned by, updated_at only has whole seconds to offer
+-- and two uploads within one would share a URL that's cached as immutable
+ALTER TABLE user_avatars
+    ADD COLUMN sha256 TEXT GENERATED ALWAYS AS (encode(sha256(data), 'hex')) STORED;
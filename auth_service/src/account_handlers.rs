@@ -0,0 +1,70 @@
+use std::net::SocketAddr;
+
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+
+use crate::{
+    auth::AuthUser,
+    chat_outbox::queue_user_deleted,
+    errors::ApiError,
+    extract::ApiJson,
+    handlers::{AppState, verify_password},
+    models::DeleteAccountRequest,
+    password_handlers::{check_throttle, find_user, record_throttled_failure},
+    throttle::{client_ip, record_success, username_key},
+    totp_handlers::verify_second_factor,
+};
+
+/// Delete your account for good, devices, keys and recovery codes go with it and every token stops working
+pub async fn delete_account(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: AuthUser,
+    ApiJson(payload): ApiJson<DeleteAccountRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = find_user(&state, auth.id()).await?;
+    let throttle_username = username_key(&user.username);
+    let ip = client_ip(addr, &headers, state.trust_forwarded_for);
+    check_throttle(&state, &throttle_username, &ip).await?;
+
+    if !verify_password(&payload.password, &user.password_hash) {
+        record_throttled_failure(&state, &throttle_username, &ip).await;
+        return Err(ApiError::Unauthorized("Password is incorrect"));
+    }
+
+    let mut transaction = state.db.begin().await?;
+    if user.totp_enabled {
+        let factor = verify_second_factor(
+            &state,
+            &mut transaction,
+            &user,
+            payload.code.as_deref(),
+            payload.recovery_code.as_deref(),
+        )
+        .await?;
+        if factor.is_none() {
+            transaction.rollback().await?;
+            record_throttled_failure(&state, &throttle_username, &ip).await;
+            return Err(ApiError::Unauthorized("Invalid code"));
+        }
+    }
+    // Devices, prekeys and recovery codes all cascade from here
+    sqlx::query("DELETE FROM users WHERE id = $1")
+        .bind(user.id)
+        .execute(&mut *transaction)
+        .await?;
+    queue_user_deleted(&mut *transaction, user.id).await?;
+    transaction.commit().await?;
+    state.chat_outbox.wake();
+
+    // The username can be registered again, don't leave it locked for whoever does
+    if let Err(err) = record_success(&state.db, &throttle_username).await {
+        eprintln!("failed to clear failed attempts: {err}");
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,71 @@
+use std::env;
+
+use axum::{
+    Json,
+    extract::State,
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    errors::ApiError,
+    extract::ApiJson,
+    handlers::AppState,
+    throttle::{ThrottleKind, unlock, username_key},
+};
+
+#[derive(Deserialize)]
+pub struct UnlockRequest {
+    pub username: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct UnlockResponse {
+    pub username_unlocked: bool,
+    pub ip_unlocked: bool,
+}
+
+// Without an ADMIN_TOKEN the admin endpoints don't exist as far as clients can tell
+fn is_admin(headers: &HeaderMap) -> Result<(), ApiError> {
+    let Ok(admin_token) = env::var("ADMIN_TOKEN") else {
+        return Err(ApiError::NotFound("Not found"));
+    };
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(ApiError::Unauthorized("Missing admin token"))?;
+    if admin_token.is_empty() || !bool::from(token.as_bytes().ct_eq(admin_token.as_bytes())) {
+        return Err(ApiError::Unauthorized("Invalid admin token"));
+    }
+    Ok(())
+}
+
+/// Clear failed login attempts and any lockout for a username and/or an IP
+pub async fn unlock_login(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    ApiJson(payload): ApiJson<UnlockRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    is_admin(&headers)?;
+    if payload.username.is_none() && payload.ip.is_none() {
+        return Err(ApiError::BadRequest("Give a username, an ip, or both"));
+    }
+
+    let mut response = UnlockResponse {
+        username_unlocked: false,
+        ip_unlocked: false,
+    };
+    if let Some(username) = &payload.username {
+        response.username_unlocked =
+            unlock(&state.db, ThrottleKind::Username, &username_key(username)).await?;
+    }
+    if let Some(ip) = &payload.ip {
+        response.ip_unlocked = unlock(&state.db, ThrottleKind::Ip, ip.trim()).await?;
+    }
+
+    Ok((StatusCode::OK, Json(response)))
+}
@@ -0,0 +1,81 @@
+use axum::{
+    extract::FromRequestParts,
+    http::{header::AUTHORIZATION, request::Parts},
+};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::{db::DbPool, errors::ApiError, handlers::AppState, jwt::decode_jwt, models::Claims};
+
+// The caller of an endpoint that needs a logged in user, with a token that hasn't been revoked
+pub struct AuthUser {
+    pub claims: Claims,
+}
+
+impl AuthUser {
+    pub fn id(&self) -> Uuid {
+        self.claims.user.id
+    }
+}
+
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or(ApiError::Unauthorized("Missing token"))?;
+        let claims =
+            decode_jwt(token).map_err(|_| ApiError::Unauthorized("Invalid or expired token"))?;
+        check_session(&state.db, &claims).await?;
+        Ok(AuthUser { claims })
+    }
+}
+
+// A logged in user, or socket_server calling with the INTERNAL_SECRET on someone's behalf
+pub enum Caller {
+    User(AuthUser),
+    Internal,
+}
+
+impl FromRequestParts<AppState> for Caller {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let token = parts
+            .headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if let (Some(token), Some(secret)) = (token, &state.internal_secret)
+            && bool::from(token.as_bytes().ct_eq(secret.as_bytes()))
+        {
+            return Ok(Caller::Internal);
+        }
+        Ok(Caller::User(
+            AuthUser::from_request_parts(parts, state).await?,
+        ))
+    }
+}
+
+// A signed token is only good until the user's session version moves past it
+pub async fn check_session(db: &DbPool, claims: &Claims) -> Result<(), ApiError> {
+    let session_version: Option<(i32,)> =
+        sqlx::query_as("SELECT session_version FROM users WHERE id = $1")
+            .bind(claims.user.id)
+            .fetch_optional(db)
+            .await?;
+    match session_version {
+        Some((version,)) if version == claims.sv => Ok(()),
+        _ => Err(ApiError::Unauthorized("Session has been revoked")),
+    }
+}
@@ -0,0 +1,95 @@
+use std::{env, time::Duration};
+
+use serde::Serialize;
+use uuid::Uuid;
+
+// Tells socket_server about account changes it has to act on straight away, like revoked sessions
+// Without SOCKET_SERVER_INTERNAL_URL and INTERNAL_SECRET it does nothing and tokens just run out on their own,
+// except deletions which wait in chat_outbox until it's set up
+#[derive(Clone)]
+pub struct ChatNotifier {
+    target: Option<(reqwest::Client, String, String)>,
+}
+
+#[derive(Serialize)]
+struct RevokeSessionsRequest {
+    session_version: i32,
+}
+
+#[derive(Serialize)]
+struct ProfileUpdatedRequest<'a> {
+    username: &'a str,
+    display_name: Option<&'a str>,
+}
+
+impl ChatNotifier {
+    pub fn from_env() -> ChatNotifier {
+        let (Ok(url), Ok(secret)) = (
+            env::var("SOCKET_SERVER_INTERNAL_URL"),
+            env::var("INTERNAL_SECRET"),
+        ) else {
+            return ChatNotifier { target: None };
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .expect("failed to build http client");
+        ChatNotifier {
+            target: Some((client, url.trim_end_matches('/').to_string(), secret)),
+        }
+    }
+
+    pub fn is_configured(&self) -> bool {
+        self.target.is_some()
+    }
+
+    // Closes every chat connection made with a token older than session_version
+    pub async fn revoke_sessions(&self, user_id: Uuid, session_version: i32) {
+        // Not worth failing the request over, the old tokens still expire within 15 minutes
+        if let Err(e) = self
+            .post(
+                &format!("users/{user_id}/revoke_sessions"),
+                &RevokeSessionsRequest { session_version },
+            )
+            .await
+        {
+            eprintln!("failed to revoke chat sessions for {user_id}: {e}");
+        }
+    }
+
+    // Closes their connections and swaps them for a placeholder in their conversations
+    // Goes through chat_outbox, which retries until this comes back Ok
+    pub async fn user_deleted(&self, user_id: Uuid) -> Result<(), reqwest::Error> {
+        self.post(&format!("users/{user_id}/deleted"), &()).await
+    }
+
+    // Messages show the new name from now on, rather than once the user's token is refreshed
+    pub async fn profile_updated(&self, user_id: Uuid, username: &str, display_name: Option<&str>) {
+        if let Err(e) = self
+            .post(
+                &format!("users/{user_id}/profile"),
+                &ProfileUpdatedRequest {
+                    username,
+                    display_name,
+                },
+            )
+            .await
+        {
+            eprintln!("failed to tell chat about {user_id}'s new profile: {e}");
+        }
+    }
+
+    async fn post(&self, path: &str, body: &impl Serialize) -> Result<(), reqwest::Error> {
+        let Some((client, url, secret)) = &self.target else {
+            return Ok(());
+        };
+        client
+            .post(format!("{url}/internal/{path}"))
+            .bearer_auth(secret)
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,115 @@
+use std::{sync::Arc, time::Duration};
+
+use sqlx::prelude::FromRow;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::{chat::ChatNotifier, db::DbPool};
+
+// Picks up anything a wake-up missed, like rows left over from before a restart
+const POLL_SECONDS: u64 = 30;
+// Each failed attempt doubles the wait, up to this while socket_server is down
+const MAX_RETRY_SECONDS: f64 = 5.0 * 60.0;
+const BATCH_SIZE: i64 = 100;
+
+// Delivers the queued chat_notifications rows, retrying until socket_server acknowledges them
+#[derive(Clone, Default)]
+pub struct ChatOutbox {
+    wake: Arc<Notify>,
+}
+
+#[derive(FromRow)]
+struct NotificationRow {
+    id: i64,
+    kind: String,
+    user_id: Uuid,
+    attempts: i32,
+}
+
+// Goes in the same transaction as the DELETE, so the deletion and the notification commit together
+pub async fn queue_user_deleted(
+    db: impl sqlx::PgExecutor<'_>,
+    user_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO chat_notifications (kind, user_id) VALUES ('user_deleted', $1)")
+        .bind(user_id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+impl ChatOutbox {
+    // Call once the transaction that queued something has committed, rather than waiting for the next poll
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    pub async fn run(self, db: DbPool, chat: ChatNotifier) {
+        if !chat.is_configured() {
+            eprintln!(
+                "SOCKET_SERVER_INTERNAL_URL or INTERNAL_SECRET not set, chat notifications stay queued until they are"
+            );
+            return;
+        }
+        let mut interval = tokio::time::interval(Duration::from_secs(POLL_SECONDS));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = self.wake.notified() => {}
+            }
+            if let Err(e) = deliver_due(&db, &chat).await {
+                eprintln!("failed to deliver chat notifications: {e}");
+            }
+        }
+    }
+}
+
+async fn deliver_due(db: &DbPool, chat: &ChatNotifier) -> Result<(), sqlx::Error> {
+    let rows = sqlx::query_as::<_, NotificationRow>(
+        "SELECT id, kind, user_id, attempts FROM chat_notifications
+         WHERE next_attempt_at <= NOW()
+         ORDER BY id
+         LIMIT $1",
+    )
+    .bind(BATCH_SIZE)
+    .fetch_all(db)
+    .await?;
+
+    for row in rows {
+        let res = match row.kind.as_str() {
+            "user_deleted" => chat.user_deleted(row.user_id).await,
+            other => {
+                eprintln!("unknown chat notification kind {other}, skipping");
+                continue;
+            }
+        };
+        match res {
+            // socket_server handles the same notification twice just fine, so a lost DELETE only costs a resend
+            Ok(()) => {
+                sqlx::query("DELETE FROM chat_notifications WHERE id = $1")
+                    .bind(row.id)
+                    .execute(db)
+                    .await?;
+            }
+            Err(e) => {
+                eprintln!(
+                    "failed to send {} for {} (attempt {}): {e}",
+                    row.kind,
+                    row.user_id,
+                    row.attempts + 1
+                );
+                let retry_seconds = 2f64.powi(row.attempts.min(16)).min(MAX_RETRY_SECONDS);
+                sqlx::query(
+                    "UPDATE chat_notifications
+                     SET attempts = attempts + 1, next_attempt_at = NOW() + make_interval(secs => $2)
+                     WHERE id = $1",
+                )
+                .bind(row.id)
+                .bind(retry_seconds)
+                .execute(db)
+                .await?;
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
+
+use uuid::Uuid;
+
+use crate::{
+    auth::Caller,
+    errors::ApiError,
+    extract::{ApiPath, ApiQuery},
+    handlers::AppState,
+    models::{FieldError, PublicUser, UserSearchQuery, UserSearchResponse},
+};
+
+const DEFAULT_SEARCH_LIMIT: i64 = 20;
+const MAX_SEARCH_LIMIT: i64 = 50;
+// Longer than any username, so it can't match anything anyway
+const MAX_PREFIX_CHARS: usize = 24;
+
+// Everything in PublicUser, the avatar URL carries a hash of the image so a new avatar is a new URL
+pub const PUBLIC_USER_SELECT: &str = "SELECT u.id, u.username, u.display_name, u.bio, u.created_at,
+        CASE WHEN a.user_id IS NULL THEN NULL
+             ELSE '/users/' || u.id || '/avatar?v=' || a.sha256
+        END AS avatar_url
+    FROM users u LEFT JOIN user_avatars a ON a.user_id = u.id";
+
+// socket_server has its own per-user limits, so only people calling us directly are counted
+pub fn check_limit(state: &AppState, caller: &Caller) -> Result<(), ApiError> {
+    let Caller::User(auth) = caller else {
+        return Ok(());
+    };
+    state
+        .directory_limiter
+        .check(auth.id())
+        .map_err(|wait| ApiError::TooManyRequests {
+            retry_after_seconds: wait.as_secs() + 1,
+        })
+}
+
+/// Find a user by their exact username, case doesn't matter
+pub async fn get_user_by_username(
+    State(state): State<AppState>,
+    caller: Caller,
+    ApiPath(username): ApiPath<String>,
+) -> Result<impl IntoResponse, ApiError> {
+    check_limit(&state, &caller)?;
+    let user = sqlx::query_as::<_, PublicUser>(&format!(
+        "{PUBLIC_USER_SELECT} WHERE LOWER(u.username) = LOWER($1)"
+    ))
+    .bind(username.trim_start_matches('@'))
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(ApiError::NotFound("User not found"))?;
+
+    Ok((StatusCode::OK, Json(user)))
+}
+
+/// Someone's public profile by their id
+pub async fn get_user(
+    State(state): State<AppState>,
+    caller: Caller,
+    ApiPath(user_id): ApiPath<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    check_limit(&state, &caller)?;
+    let user = sqlx::query_as::<_, PublicUser>(&format!("{PUBLIC_USER_SELECT} WHERE u.id = $1"))
+        .bind(user_id)
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(ApiError::NotFound("User not found"))?;
+
+    Ok((StatusCode::OK, Json(user)))
+}
+
+/// Users whose username starts with prefix, in username order and paged with next_cursor
+pub async fn search_users(
+    State(state): State<AppState>,
+    caller: Caller,
+    ApiQuery(query): ApiQuery<UserSearchQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    check_limit(&state, &caller)?;
+
+    let prefix = query.prefix.trim().trim_start_matches('@').to_lowercase();
+    if prefix.is_empty()
+        || prefix.chars().count() > MAX_PREFIX_CHARS
+        || !prefix
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "prefix",
+            message: "Prefix must be 1-24 letters, digits or underscores",
+        }]));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+
+    // Underscores are allowed in usernames but are a wildcard to LIKE
+    let pattern = format!("{}%", prefix.replace('_', "\\_"));
+    let mut users = sqlx::query_as::<_, PublicUser>(&format!(
+        "{PUBLIC_USER_SELECT}
+         WHERE LOWER(u.username) LIKE $1 ESCAPE '\\'
+           AND ($2::TEXT IS NULL OR LOWER(u.username) > $2)
+         ORDER BY LOWER(u.username)
+         LIMIT $3"
+    ))
+    .bind(&pattern)
+    .bind(query.cursor.as_deref().map(str::to_lowercase))
+    .bind(limit + 1)
+    .fetch_all(&state.db)
+    .await?;
+
+    // Asked for one extra to know if there's another page
+    let next_cursor = if users.len() as i64 > limit {
+        users.truncate(limit as usize);
+        users.last().map(|u| u.username.to_lowercase())
+    } else {
+        None
+    };
+
+    Ok((
+        StatusCode::OK,
+        Json(UserSearchResponse { users, next_cursor }),
+    ))
+}
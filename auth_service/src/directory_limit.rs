@@ -0,0 +1,41 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use uuid::Uuid;
+
+// Enough to search as you type, not enough to walk the whole user list
+const LOOKUPS_PER_WINDOW: u32 = 60;
+const WINDOW: Duration = Duration::from_secs(60);
+// Past this many users in the map, windows that have ended are dropped on the next check
+const PRUNE_OVER: usize = 10_000;
+
+// Per user fixed windows, only kept in memory so a restart forgets them
+#[derive(Clone, Default)]
+pub struct DirectoryLimiter {
+    windows: Arc<Mutex<HashMap<Uuid, (Instant, u32)>>>,
+}
+
+impl DirectoryLimiter {
+    // How long until the user may look someone up again, if they're over the limit
+    pub fn check(&self, user_id: Uuid) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        if windows.len() > PRUNE_OVER {
+            windows.retain(|_, (started, _)| now.duration_since(*started) < WINDOW);
+        }
+
+        let (started, count) = windows.entry(user_id).or_insert((now, 0));
+        if now.duration_since(*started) >= WINDOW {
+            *started = now;
+            *count = 0;
+        }
+        if *count >= LOOKUPS_PER_WINDOW {
+            return Err(WINDOW - now.duration_since(*started));
+        }
+        *count += 1;
+        Ok(())
+    }
+}
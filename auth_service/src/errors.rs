@@ -0,0 +1,171 @@
+use axum::{
+    Json,
+    extract::rejection::{JsonRejection, PathRejection, QueryRejection},
+    http::{HeaderValue, StatusCode, header::CONTENT_TYPE, header::RETRY_AFTER},
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+use crate::models::FieldError;
+
+// Everything a handler can fail with, the client only ever sees the status and a safe message
+#[derive(Debug)]
+pub enum ApiError {
+    BadRequest(&'static str),
+    Validation(Vec<FieldError>),
+    Unauthorized(&'static str),
+    Forbidden(&'static str),
+    NotFound(&'static str),
+    Conflict(&'static str),
+    PayloadTooLarge(&'static str),
+    UnsupportedMediaType(&'static str),
+    TooManyRequests { retry_after_seconds: u64 },
+    Database(sqlx::Error),
+    Internal(String),
+}
+
+// RFC 7807, with our own `errors` and `retry_after` members where they apply
+#[derive(Serialize)]
+struct Problem {
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    title: &'static str,
+    status: u16,
+    detail: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> ApiError {
+        ApiError::Database(err)
+    }
+}
+
+// What axum's own extractors fail with, see extract.rs, so bad requests get the same problem+json as everything else
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> ApiError {
+        match rejection {
+            JsonRejection::MissingJsonContentType(_) => {
+                ApiError::UnsupportedMediaType("Expected a JSON body")
+            }
+            JsonRejection::JsonSyntaxError(_) => ApiError::BadRequest("The body isn't valid JSON"),
+            // Missing fields, wrong types...
+            JsonRejection::JsonDataError(_) => ApiError::Validation(vec![]),
+            rejection if rejection.status() == StatusCode::PAYLOAD_TOO_LARGE => {
+                ApiError::PayloadTooLarge("The body is too large")
+            }
+            _ => ApiError::BadRequest("Couldn't read the body"),
+        }
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> ApiError {
+        match rejection {
+            PathRejection::FailedToDeserializePathParams(_) => {
+                ApiError::BadRequest("Invalid value in the path")
+            }
+            // A route and its handler disagree, that's on us
+            rejection => ApiError::Internal(rejection.body_text()),
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(_: QueryRejection) -> ApiError {
+        ApiError::BadRequest("Invalid query string")
+    }
+}
+
+impl ApiError {
+    fn status(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ApiError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Database(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn detail(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(detail)
+            | ApiError::Unauthorized(detail)
+            | ApiError::Forbidden(detail)
+            | ApiError::NotFound(detail)
+            | ApiError::Conflict(detail)
+            | ApiError::PayloadTooLarge(detail)
+            | ApiError::UnsupportedMediaType(detail) => detail,
+            ApiError::Validation(_) => "The request has invalid fields",
+            ApiError::TooManyRequests { .. } => "Too many attempts, try again later",
+            ApiError::Database(_) | ApiError::Internal(_) => "Something went wrong on our end",
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let detail = self.detail();
+
+        // The details stay in our logs, clients get the generic detail above
+        match &self {
+            ApiError::Database(err) => eprintln!("database error: {err}"),
+            ApiError::Internal(err) => eprintln!("internal error: {err}"),
+            _ => {}
+        }
+
+        let retry_after = match &self {
+            ApiError::TooManyRequests {
+                retry_after_seconds,
+            } => Some(*retry_after_seconds),
+            _ => None,
+        };
+        let problem = Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail,
+            errors: match self {
+                ApiError::Validation(errors) => errors,
+                _ => vec![],
+            },
+            retry_after,
+        };
+
+        let mut response = (status, Json(problem)).into_response();
+        let headers = response.headers_mut();
+        headers.insert(
+            CONTENT_TYPE,
+            HeaderValue::from_static("application/problem+json"),
+        );
+        if let Some(seconds) = retry_after {
+            headers.insert(RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response
+    }
+}
+
+// Postgres error codes we turn into something other than a 500
+pub fn is_unique_violation(err: &sqlx::Error) -> bool {
+    has_code(err, "23505")
+}
+
+pub fn is_foreign_key_violation(err: &sqlx::Error) -> bool {
+    has_code(err, "23503")
+}
+
+fn has_code(err: &sqlx::Error, code: &str) -> bool {
+    err.as_database_error()
+        .and_then(|e| e.code())
+        .is_some_and(|c| c == code)
+}
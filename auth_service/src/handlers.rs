@@ -1,25 +1,47 @@
-use std::env;
+use std::{net::SocketAddr, sync::LazyLock};
 
 use argon2::{
     Argon2, PasswordHash, PasswordVerifier,
     password_hash::{PasswordHasher, SaltString, rand_core::OsRng},
 };
-use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
-use jsonwebtoken::{DecodingKey, Validation, decode};
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
 use uuid::Uuid;
 
 use crate::{
+    auth::check_session,
+    chat::ChatNotifier,
+    chat_outbox::ChatOutbox,
     db::DbPool,
-    jwt::create_jwt,
+    directory_limit::DirectoryLimiter,
+    errors::{ApiError, is_unique_violation},
+    extract::ApiJson,
+    jwt::{MFA_CHALLENGE_SECONDS, create_jwt, create_mfa_challenge, decode_jwt},
     models::{
-        Claims, CreateUserRequest, CreateUserResponse, GenericServerError, LoginResponse, User,
-        VerifyRequest,
+        CreateUserRequest, CreateUserResponse, LoginRequest, LoginResponse, MfaChallengeResponse,
+        RegisterResponse, User, VerifyRequest,
     },
+    recovery::replace_recovery_codes,
+    throttle::{client_ip, locked_for, record_failure, record_success, username_key},
+    validation::validate_registration,
 };
 
+// Verified against when the username doesn't exist, so those logins take as long as real ones
+pub static DUMMY_PASSWORD_HASH: LazyLock<String> =
+    LazyLock::new(|| hash_password("not a real password, just here to burn time"));
+
 #[derive(Clone)]
 pub struct AppState {
     pub db: DbPool,
+    pub trust_forwarded_for: bool, // Only when running behind a proxy that sets X-Forwarded-For
+    pub chat: ChatNotifier,
+    pub chat_outbox: ChatOutbox, // For notifications that have to get there, see chat_outbox
+    pub internal_secret: Option<String>, // socket_server's, for looking users up without a user token
+    pub directory_limiter: DirectoryLimiter,
 }
 
 pub async fn root() -> &'static str {
@@ -28,115 +50,168 @@ pub async fn root() -> &'static str {
 
 pub async fn register(
     State(state): State<AppState>,
-    Json(payload): Json<CreateUserRequest>,
-) -> impl IntoResponse {
+    ApiJson(payload): ApiJson<CreateUserRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let errors = validate_registration(&payload.username, &payload.password);
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+
     let id = Uuid::new_v4();
     let hashed_password = hash_password(&payload.password);
-    let res = sqlx::query_as::<_, CreateUserResponse>(
-        "INSERT INTO users (id, username, password_hash) VALUES ($1, $2, $3) RETURNING id, username, created_at, updated_at",
-    ).bind(id).bind(&payload.username).bind(hashed_password).fetch_one(&state.db).await;
-
-    match res {
-        Ok(user) => (
-            StatusCode::CREATED,
-            Json(CreateUserResponse {
-                id: user.id,
-                username: user.username,
-                created_at: user.created_at,
-                updated_at: user.updated_at,
-            }),
-        )
-            .into_response(),
-        Err(err) => {
-            // Basic error mapping
-            let msg = format!("DB Error: {}", err);
-            (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response()
+    let mut transaction = state.db.begin().await?;
+    let user = sqlx::query_as::<_, CreateUserResponse>(
+        "INSERT INTO users (id, username, password_hash) VALUES ($1, $2, $3) RETURNING id, username, display_name, created_at, updated_at",
+    ).bind(id).bind(&payload.username).bind(hashed_password).fetch_one(&mut *transaction).await
+    .map_err(|err| {
+        // Caught by idx_users_username_lower, so it also covers the same name in a different case
+        if is_unique_violation(&err) {
+            ApiError::Conflict("Username is already taken")
+        } else {
+            ApiError::Database(err)
         }
-    }
+    })?;
+    let recovery_codes = replace_recovery_codes(&mut transaction, user.id).await?;
+    transaction.commit().await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(RegisterResponse {
+            user,
+            recovery_codes,
+        }),
+    ))
 }
 
-// TODO: Solve timing attack vulnerability and better db error handling
 pub async fn login(
     State(state): State<AppState>,
-    Json(payload): Json<CreateUserRequest>, // Same structure as create, just username and password, for now
-) -> impl IntoResponse {
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ApiJson(payload): ApiJson<LoginRequest>,
+) -> Result<Response, ApiError> {
+    let throttle_username = username_key(&payload.username);
+    let ip = client_ip(addr, &headers, state.trust_forwarded_for);
+
+    // Checked before touching the password, a locked account can't be guessed at even with the right one
+    if let Some(wait) = locked_for(&state.db, &throttle_username, &ip).await? {
+        return Err(ApiError::TooManyRequests {
+            retry_after_seconds: wait.as_secs() + 1,
+        });
+    }
+
     let res = sqlx::query_as::<_, User>(
-        "SELECT id, username, password_hash, created_at, updated_at FROM users WHERE username = $1",
+        "SELECT id, username, password_hash, session_version, totp_enabled, display_name, created_at, updated_at FROM users WHERE LOWER(username) = LOWER($1)",
     )
     .bind(&payload.username)
     .fetch_one(&state.db)
     .await;
     let user = match res {
-        Ok(user) => user,
-        Err(err) => {
-            let msg = format!("DB Error: {}", err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response();
+        Ok(user) => Some(user),
+        Err(sqlx::Error::RowNotFound) => None,
+        Err(err) => return Err(ApiError::Database(err)),
+    };
+
+    // Unknown usernames still pay for an Argon2 verification and get the exact same answer,
+    // so neither the response nor its timing says whether the account exists
+    let password_matches = match &user {
+        Some(user) => verify_password(&payload.password, &user.password_hash),
+        None => {
+            verify_password(&payload.password, &DUMMY_PASSWORD_HASH);
+            false
+        }
+    };
+    let Some(user) = user.filter(|_| password_matches) else {
+        if let Err(err) = record_failure(&state.db, &throttle_username, &ip).await {
+            eprintln!("failed to record failed login: {err}");
         }
+        return Err(ApiError::Unauthorized("Invalid username or password"));
     };
-    if !verify_password(&payload.password, &user.password_hash) {
-        return (StatusCode::UNAUTHORIZED, "Invalid username or password").into_response();
+    // The device ends up in the token, so make sure it's actually one of this user's
+    if let Some(device_id) = payload.device_id {
+        let device = sqlx::query("SELECT id FROM devices WHERE id = $1 AND user_id = $2")
+            .bind(device_id)
+            .bind(user.id)
+            .fetch_optional(&state.db)
+            .await?;
+        if device.is_none() {
+            return Err(ApiError::Unauthorized("Unknown device"));
+        }
+    }
+
+    // The password was right, but with TOTP on that only gets you as far as the second step
+    if user.totp_enabled {
+        let challenge_token =
+            create_mfa_challenge(user.id, payload.device_id, user.session_version)
+                .map_err(|e| ApiError::Internal(format!("failed to create challenge: {e}")))?;
+        return Ok((
+            StatusCode::OK,
+            Json(MfaChallengeResponse {
+                mfa_required: true,
+                challenge_token,
+                expires_in: MFA_CHALLENGE_SECONDS,
+            }),
+        )
+            .into_response());
+    }
+
+    // Only now, with TOTP on the password alone doesn't get to reset the throttle
+    if let Err(err) = record_success(&state.db, &throttle_username).await {
+        eprintln!("failed to clear failed logins: {err}");
     }
 
-    let token = match create_jwt(
+    Ok((
+        StatusCode::OK,
+        Json(login_response(user, payload.device_id)?),
+    )
+        .into_response())
+}
+
+// A session token for the user, as handed out by every way of logging in
+pub fn login_response(user: User, device_id: Option<i64>) -> Result<LoginResponse, ApiError> {
+    let token = create_jwt(
         &user.id.to_string(),
         CreateUserResponse {
             username: user.username.clone(),
+            display_name: user.display_name.clone(),
             created_at: user.created_at,
             updated_at: user.updated_at,
             id: user.id,
         },
-    ) {
-        Ok(token) => token,
-        Err(e) => {
-            let msg = format!("Token Error: {}", e);
-            return (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response();
-        }
-    };
-
-    (
-        StatusCode::OK,
-        Json(LoginResponse {
-            id: user.id,
-            username: user.username,
-            token: token,
-            created_at: user.created_at,
-            updated_at: user.updated_at,
-        }),
+        device_id,
+        user.session_version,
     )
-        .into_response()
+    .map_err(|e| ApiError::Internal(format!("failed to create token: {e}")))?;
+
+    Ok(LoginResponse {
+        id: user.id,
+        username: user.username,
+        token,
+        device_id,
+        created_at: user.created_at,
+        updated_at: user.updated_at,
+    })
 }
 
-pub async fn verify(Json(payload): Json<VerifyRequest>) -> impl IntoResponse {
-    let claims = match decode::<Claims>(
-        &payload.token,
-        &DecodingKey::from_secret(env::var("JWT_SECRET").expect("no JWT_SECRET set").as_ref()),
-        &Validation::default(),
-    ) {
-        Err(e) => {
-            return (
-                StatusCode::UNAUTHORIZED,
-                Json(GenericServerError {
-                    code: 401,
-                    message: e.to_string(),
-                }),
-            )
-                .into_response();
-        }
-        Ok(c) => c,
-    };
-    (
+pub async fn verify(
+    State(state): State<AppState>,
+    ApiJson(payload): ApiJson<VerifyRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let claims = decode_jwt(&payload.token)
+        .map_err(|_| ApiError::Unauthorized("Invalid or expired token"))?;
+    check_session(&state.db, &claims).await?;
+    Ok((
         StatusCode::OK,
         Json(CreateUserResponse {
-            created_at: claims.claims.user.created_at,
-            updated_at: claims.claims.user.updated_at,
-            id: claims.claims.user.id,
-            username: claims.claims.user.username,
+            created_at: claims.user.created_at,
+            updated_at: claims.user.updated_at,
+            id: claims.user.id,
+            username: claims.user.username,
+            display_name: claims.user.display_name,
         }),
-    )
-        .into_response()
+    ))
 }
 
-fn hash_password(password: &str) -> String {
+pub fn hash_password(password: &str) -> String {
     let salt = SaltString::generate(&mut OsRng);
     let argon2 = Argon2::default();
     let password_hash = argon2
@@ -146,7 +221,7 @@ fn hash_password(password: &str) -> String {
     password_hash.to_string()
 }
 
-fn verify_password(password: &str, stored_hash: &str) -> bool {
+pub fn verify_password(password: &str, stored_hash: &str) -> bool {
     let parsed_hash = PasswordHash::new(stored_hash).expect("Invalid hash");
     let argon2 = Argon2::default();
     argon2
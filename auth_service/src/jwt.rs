@@ -1,12 +1,18 @@
 use chrono::Utc;
-use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation, decode, encode};
 use std::{env, time::Duration};
+use uuid::Uuid;
 
-use crate::models::{Claims, CreateUserResponse};
+use crate::models::{Claims, CreateUserResponse, MfaChallengeClaims};
+
+const MFA_PURPOSE: &str = "mfa";
+pub const MFA_CHALLENGE_SECONDS: u64 = 5 * 60;
 
 pub fn create_jwt(
     user_id: &str,
     user: CreateUserResponse,
+    device_id: Option<i64>,
+    session_version: i32,
 ) -> Result<String, jsonwebtoken::errors::Error> {
     let secret = env::var("JWT_SECRET").expect("no JWT_SECRET set");
     let now = Utc::now();
@@ -15,6 +21,8 @@ pub fn create_jwt(
     let claims = Claims {
         sub: user_id.to_owned(),
         user,
+        device_id,
+        sv: session_version,
         iat: now.timestamp() as usize,
         exp: expiration.timestamp() as usize,
     };
@@ -24,3 +32,51 @@ pub fn create_jwt(
 
     encode(&header, &claims, &encoding_key)
 }
+
+// Only checks the signature and expiry, see auth::check_session for revocation
+pub fn decode_jwt(token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+    let secret = env::var("JWT_SECRET").expect("no JWT_SECRET set");
+    decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )
+    .map(|data| data.claims)
+}
+
+// Short lived, and shaped differently from Claims so neither can be passed off as the other
+pub fn create_mfa_challenge(
+    user_id: Uuid,
+    device_id: Option<i64>,
+    session_version: i32,
+) -> Result<String, jsonwebtoken::errors::Error> {
+    let secret = env::var("JWT_SECRET").expect("no JWT_SECRET set");
+    let now = Utc::now();
+    let expiration = now + Duration::from_secs(MFA_CHALLENGE_SECONDS);
+
+    let claims = MfaChallengeClaims {
+        sub: user_id,
+        purpose: MFA_PURPOSE.to_string(),
+        device_id,
+        sv: session_version,
+        iat: now.timestamp() as usize,
+        exp: expiration.timestamp() as usize,
+    };
+    encode(
+        &Header::new(Algorithm::HS256),
+        &claims,
+        &EncodingKey::from_secret(secret.as_bytes()),
+    )
+}
+
+pub fn decode_mfa_challenge(token: &str) -> Option<MfaChallengeClaims> {
+    let secret = env::var("JWT_SECRET").expect("no JWT_SECRET set");
+    decode::<MfaChallengeClaims>(
+        token,
+        &DecodingKey::from_secret(secret.as_bytes()),
+        &Validation::default(),
+    )
+    .ok()
+    .map(|data| data.claims)
+    .filter(|claims| claims.purpose == MFA_PURPOSE)
+}
@@ -1,13 +1,11 @@
-use axum::{
-    Json,
-    extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
-};
+use axum::{Json, extract::State, http::StatusCode, response::IntoResponse};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
+    auth::AuthUser,
+    errors::{ApiError, is_foreign_key_violation, is_unique_violation},
+    extract::{ApiJson, ApiPath},
     handlers::AppState,
     models::{Device, OneTimePreKey},
 };
@@ -50,13 +48,22 @@ pub struct OneTimePreKeyResponse {
     pub public_key: Vec<u8>,
 }
 
-/// Upload a new device with its cryptographic keys
+/// Upload a new device with its cryptographic keys, only for your own account
 pub async fn upload_device(
     State(state): State<AppState>,
-    Path(user_id): Path<Uuid>,
-    Json(payload): Json<UploadDeviceRequest>,
-) -> impl IntoResponse {
-    // First, insert the device
+    auth: AuthUser,
+    ApiPath(user_id): ApiPath<Uuid>,
+    ApiJson(payload): ApiJson<UploadDeviceRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    // Otherwise anyone could slip their own identity key in among someone else's devices
+    if auth.id() != user_id {
+        return Err(ApiError::Forbidden(
+            "You can only add devices to your own account",
+        ));
+    }
+
+    // The device and its prekeys go in together, or not at all
+    let mut transaction = state.db.begin().await?;
     let device_result = sqlx::query_as::<_, Device>(
         r#"
         INSERT INTO devices (user_id, device_name, identity_key_public, signed_prekey_id, signed_prekey_public, signed_prekey_signature)
@@ -70,108 +77,111 @@ pub async fn upload_device(
     .bind(payload.signed_prekey_id)
     .bind(&payload.signed_prekey_public)
     .bind(&payload.signed_prekey_signature)
-    .fetch_one(&state.db)
+    .fetch_one(&mut *transaction)
     .await;
 
-    let device = match device_result {
-        Ok(d) => d,
-        Err(err) => {
-            let msg = format!("Failed to create device: {}", err);
-            return (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response();
+    let device = device_result.map_err(|err| {
+        if is_unique_violation(&err) {
+            ApiError::Conflict("Device name is already in use")
+        } else if is_foreign_key_violation(&err) {
+            ApiError::NotFound("User not found")
+        } else {
+            ApiError::Database(err)
         }
-    };
+    })?;
 
-    // Insert one-time prekeys
     for prekey in &payload.one_time_prekeys {
-        let _ = sqlx::query(
+        sqlx::query(
             "INSERT INTO one_time_prekeys (device_id, key_id, public_key) VALUES ($1, $2, $3)",
         )
         .bind(device.id)
         .bind(prekey.key_id)
         .bind(&prekey.public_key)
-        .execute(&state.db)
-        .await;
-        // Ignore errors for PoC - in production, you'd handle this properly
+        .execute(&mut *transaction)
+        .await
+        .map_err(|err| {
+            if is_unique_violation(&err) {
+                ApiError::Conflict("One-time prekey ids must be unique")
+            } else {
+                ApiError::Database(err)
+            }
+        })?;
     }
+    transaction.commit().await?;
 
-    (
+    Ok((
         StatusCode::CREATED,
         Json(UploadDeviceResponse {
             device_id: device.id,
-            message: format!("Device created with {} one-time prekeys", payload.one_time_prekeys.len()),
+            message: format!(
+                "Device created with {} one-time prekeys",
+                payload.one_time_prekeys.len()
+            ),
         }),
-    )
-        .into_response()
+    ))
 }
 
-/// Fetch a prekey bundle for a user (for establishing encrypted session)
-/// This consumes one one-time prekey if available
+/// Fetch a prekey bundle for every device of a user (for establishing encrypted sessions)
+/// This consumes one one-time prekey per device if available
 pub async fn get_prekey_bundle(
     State(state): State<AppState>,
-    Path(user_id): Path<Uuid>,
-) -> impl IntoResponse {
-    // For PoC: just get the first device for this user
-    // In production: handle multiple devices
-    let device_result = sqlx::query_as::<_, Device>(
+    _auth: AuthUser,
+    ApiPath(user_id): ApiPath<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    // Senders encrypt once per recipient device, so they need a session with each of them
+    let devices = match sqlx::query_as::<_, Device>(
         "SELECT id, user_id, device_name, identity_key_public, signed_prekey_id, signed_prekey_public, signed_prekey_signature, created_at 
-         FROM devices WHERE user_id = $1 LIMIT 1",
+         FROM devices WHERE user_id = $1 ORDER BY id",
     )
     .bind(user_id)
-    .fetch_one(&state.db)
-    .await;
-
-    let device = match device_result {
-        Ok(d) => d,
-        Err(_) => {
-            return (StatusCode::NOT_FOUND, "No device found for user").into_response();
-        }
+    .fetch_all(&state.db)
+    .await?
+    {
+        d if !d.is_empty() => d,
+        _ => return Err(ApiError::NotFound("No device found for user")),
     };
 
-    // Try to get and consume one one-time prekey
-    let one_time_prekey = sqlx::query_as::<_, OneTimePreKey>(
-        "DELETE FROM one_time_prekeys WHERE id = (
-            SELECT id FROM one_time_prekeys WHERE device_id = $1 LIMIT 1
-         ) RETURNING id, device_id, key_id, public_key, created_at",
-    )
-    .bind(device.id)
-    .fetch_optional(&state.db)
-    .await
-    .ok()
-    .flatten();
-
-    let bundle = PreKeyBundle {
-        device_id: device.id,
-        identity_key_public: device.identity_key_public,
-        signed_prekey_id: device.signed_prekey_id,
-        signed_prekey_public: device.signed_prekey_public,
-        signed_prekey_signature: device.signed_prekey_signature,
-        one_time_prekey: one_time_prekey.map(|otpk| OneTimePreKeyResponse {
-            key_id: otpk.key_id,
-            public_key: otpk.public_key,
-        }),
-    };
+    let mut bundles: Vec<PreKeyBundle> = Vec::with_capacity(devices.len());
+    for device in devices {
+        // Try to get and consume one one-time prekey
+        let one_time_prekey = sqlx::query_as::<_, OneTimePreKey>(
+            "DELETE FROM one_time_prekeys WHERE id = (
+                SELECT id FROM one_time_prekeys WHERE device_id = $1 LIMIT 1
+             ) RETURNING id, device_id, key_id, public_key, created_at",
+        )
+        .bind(device.id)
+        .fetch_optional(&state.db)
+        .await?;
+
+        bundles.push(PreKeyBundle {
+            device_id: device.id,
+            identity_key_public: device.identity_key_public,
+            signed_prekey_id: device.signed_prekey_id,
+            signed_prekey_public: device.signed_prekey_public,
+            signed_prekey_signature: device.signed_prekey_signature,
+            one_time_prekey: one_time_prekey.map(|otpk| OneTimePreKeyResponse {
+                key_id: otpk.key_id,
+                public_key: otpk.public_key,
+            }),
+        });
+    }
 
-    (StatusCode::OK, Json(bundle)).into_response()
+    Ok((StatusCode::OK, Json(bundles)))
 }
 
 /// Get list of devices for a user (useful for multi-device)
 pub async fn list_user_devices(
     State(state): State<AppState>,
-    Path(user_id): Path<Uuid>,
-) -> impl IntoResponse {
+    _auth: AuthUser,
+    ApiPath(user_id): ApiPath<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
     let devices = sqlx::query_as::<_, Device>(
         "SELECT id, user_id, device_name, identity_key_public, signed_prekey_id, signed_prekey_public, signed_prekey_signature, created_at 
          FROM devices WHERE user_id = $1",
     )
     .bind(user_id)
     .fetch_all(&state.db)
-    .await;
+    .await?;
 
-    match devices {
-        Ok(devices) => (StatusCode::OK, Json(devices)).into_response(),
-        Err(err) => {
-            let msg = format!("Failed to fetch devices: {}", err);
-            (StatusCode::INTERNAL_SERVER_ERROR, msg).into_response()
-        }
-    }
+    Ok((StatusCode::OK, Json(devices)))
 }
@@ -1,11 +1,35 @@
+mod account_handlers;
+mod admin_handlers;
+mod auth;
+mod chat;
+mod chat_outbox;
 mod db;
+mod directory_handlers;
+mod directory_limit;
+mod errors;
+mod extract;
 mod handlers;
 mod jwt;
+mod key_handlers;
 mod models;
+mod password_handlers;
+mod profile_handlers;
+mod recovery;
 mod routes;
+mod throttle;
+mod totp;
+mod totp_handlers;
+mod validation;
 
-use crate::{db::connect, handlers::AppState, routes::create_router};
-use std::env;
+use crate::{
+    chat::ChatNotifier,
+    chat_outbox::ChatOutbox,
+    db::{DbPool, connect},
+    directory_limit::DirectoryLimiter,
+    handlers::{AppState, DUMMY_PASSWORD_HASH},
+    routes::create_router,
+};
+use std::{env, net::SocketAddr, sync::LazyLock, time::Duration};
 
 #[tokio::main]
 async fn main() {
@@ -14,9 +38,43 @@ async fn main() {
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL not set");
     let pool = connect(&database_url).await;
 
-    let state = AppState { db: pool };
+    // Hash it now rather than on the first unknown username, which would stand out
+    LazyLock::force(&DUMMY_PASSWORD_HASH);
+
+    let trust_forwarded_for = env::var("TRUST_FORWARDED_FOR").is_ok_and(|v| v == "true");
+
+    tokio::spawn(prune_login_throttles(pool.clone()));
+
+    let chat = ChatNotifier::from_env();
+    let chat_outbox = ChatOutbox::default();
+    tokio::spawn(chat_outbox.clone().run(pool.clone(), chat.clone()));
+
+    let state = AppState {
+        db: pool,
+        trust_forwarded_for,
+        chat,
+        chat_outbox,
+        internal_secret: env::var("INTERNAL_SECRET").ok().filter(|s| !s.is_empty()),
+        directory_limiter: DirectoryLimiter::default(),
+    };
     let app = create_router(state);
 
     let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    // Login needs the peer address for per-IP throttling
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+async fn prune_login_throttles(db: DbPool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(10 * 60));
+    loop {
+        interval.tick().await;
+        if let Err(e) = throttle::prune(&db).await {
+            eprintln!("failed to prune login throttles: {e}");
+        }
+    }
 }
@@ -10,6 +10,9 @@ pub struct User {
     pub id: Uuid,
     pub username: String,
     pub password_hash: String,
+    pub session_version: i32,
+    pub totp_enabled: bool,
+    pub display_name: Option<String>,
 
     #[serde(with = "chrono::serde::ts_seconds")]
     pub created_at: DateTime<Utc>,
@@ -24,10 +27,21 @@ pub struct CreateUserRequest {
     pub password: String,
 }
 
+#[derive(Deserialize)]
+pub struct LoginRequest {
+    pub username: String,
+    pub password: String,
+    // Optional so a fresh account can log in before it has uploaded any device keys
+    #[serde(default)]
+    pub device_id: Option<i64>,
+}
+
 #[derive(Serialize, FromRow, Deserialize)]
 pub struct CreateUserResponse {
     pub id: Uuid,
     pub username: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub display_name: Option<String>,
     #[serde(with = "chrono::serde::ts_seconds")]
     pub created_at: DateTime<Utc>,
 
@@ -35,11 +49,20 @@ pub struct CreateUserResponse {
     pub updated_at: DateTime<Utc>,
 }
 
+// The recovery codes are only ever shown here, we only keep their hashes
+#[derive(Serialize)]
+pub struct RegisterResponse {
+    #[serde(flatten)]
+    pub user: CreateUserResponse,
+    pub recovery_codes: Vec<String>,
+}
+
 #[derive(Serialize)]
 pub struct LoginResponse {
     pub id: Uuid,
     pub username: String,
     pub token: String,
+    pub device_id: Option<i64>,
     #[serde(with = "chrono::serde::ts_seconds")]
     pub created_at: DateTime<Utc>,
 
@@ -47,35 +70,51 @@ pub struct LoginResponse {
     pub updated_at: DateTime<Utc>,
 }
 
-// #[derive(Serialize, FromRow)]
-// pub struct Device {
-//     pub id: i64,
-//     pub user_id: Uuid,
-//     pub device_name: String,
-//     pub identity_key_public: Vec<u8>,
-//     pub signed_prekey_id: i32,
-//     pub signed_prekey_public: Vec<u8>,
-//     pub signed_prekey_signature: Vec<u8>,
+#[derive(Serialize, FromRow)]
+pub struct Device {
+    pub id: i64,
+    pub user_id: Uuid,
+    pub device_name: String,
+    pub identity_key_public: Vec<u8>,
+    pub signed_prekey_id: i32,
+    pub signed_prekey_public: Vec<u8>,
+    pub signed_prekey_signature: Vec<u8>,
 
-//     #[serde(with = "chrono::serde::ts_seconds")]
-//     pub created_at: DateTime<Utc>,
-// }
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub created_at: DateTime<Utc>,
+}
 
-// #[derive(Serialize, FromRow)]
-// pub struct OneTimePreKey {
-//     pub id: i64,
-//     pub device_id: i64,
-//     pub key_id: i32,
-//     pub public_key: Vec<u8>,
+#[derive(Serialize, FromRow)]
+pub struct OneTimePreKey {
+    pub id: i64,
+    pub device_id: i64,
+    pub key_id: i32,
+    pub public_key: Vec<u8>,
 
-//     #[serde(with = "chrono::serde::ts_seconds")]
-//     pub created_at: DateTime<Utc>,
-// }
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub created_at: DateTime<Utc>,
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
     pub user: CreateUserResponse,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<i64>,
+    #[serde(default)]
+    pub sv: i32, // users.session_version when the token was issued
+    pub exp: usize,
+    pub iat: usize,
+}
+
+// Proves the password step of a login passed, only good for POST /login/mfa
+#[derive(Serialize, Deserialize)]
+pub struct MfaChallengeClaims {
+    pub sub: Uuid,
+    pub purpose: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<i64>,
+    pub sv: i32,
     pub exp: usize,
     pub iat: usize,
 }
@@ -85,8 +124,129 @@ pub struct VerifyRequest {
     pub token: String,
 }
 
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub field: &'static str,
+    pub message: &'static str,
+}
+
+#[derive(Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+    pub username: String,
+    pub recovery_code: String,
+    pub new_password: String,
+}
+
+#[derive(Serialize)]
+pub struct ResetPasswordResponse {
+    pub remaining_recovery_codes: i64,
+}
+
+#[derive(Deserialize)]
+pub struct RegenerateRecoveryCodesRequest {
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodesResponse {
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct MfaChallengeResponse {
+    pub mfa_required: bool,
+    pub challenge_token: String,
+    pub expires_in: u64,
+}
+
+// One of code or recovery_code, a recovery code also turns TOTP off
+#[derive(Deserialize)]
+pub struct MfaLoginRequest {
+    pub challenge_token: String,
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub recovery_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TotpSetupRequest {
+    pub password: String,
+}
+
+#[derive(Serialize)]
+pub struct TotpSetupResponse {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Deserialize)]
+pub struct TotpDisableRequest {
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub recovery_code: Option<String>,
+}
+
 #[derive(Serialize)]
-pub struct GenericServerError {
-    pub code: i32,
-    pub message: String,
+pub struct TotpStatusResponse {
+    pub totp_enabled: bool,
+}
+
+// code or recovery_code is only needed when TOTP is on
+#[derive(Deserialize)]
+pub struct DeleteAccountRequest {
+    pub password: String,
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub recovery_code: Option<String>,
+}
+
+// What anyone logged in may see about another user
+#[derive(Serialize, FromRow)]
+pub struct PublicUser {
+    pub id: Uuid,
+    pub username: String,
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar_url: Option<String>, // Changes whenever the avatar does, so it can be cached forever
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Deserialize)]
+pub struct UserSearchQuery {
+    pub prefix: String,
+    #[serde(default)]
+    pub limit: Option<i64>,
+    #[serde(default)]
+    pub cursor: Option<String>, // next_cursor from the previous page
+}
+
+#[derive(Serialize)]
+pub struct UserSearchResponse {
+    pub users: Vec<PublicUser>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+// Missing fields stay as they are, an empty string clears one
+#[derive(Deserialize)]
+pub struct UpdateProfileRequest {
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub bio: Option<String>,
 }
@@ -0,0 +1,191 @@
+use std::net::SocketAddr;
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthUser,
+    errors::ApiError,
+    extract::ApiJson,
+    handlers::{AppState, hash_password, login_response, verify_password},
+    models::{
+        ChangePasswordRequest, FieldError, RecoveryCodesResponse, RegenerateRecoveryCodesRequest,
+        ResetPasswordRequest, ResetPasswordResponse, User,
+    },
+    recovery::{remaining_recovery_codes, replace_recovery_codes, use_recovery_code},
+    throttle::{client_ip, locked_for, record_failure, record_success, username_key},
+    validation::validate_password,
+};
+
+// Guessing the current password or a recovery code is throttled just like logging in
+pub async fn check_throttle(state: &AppState, username: &str, ip: &str) -> Result<(), ApiError> {
+    match locked_for(&state.db, username, ip).await? {
+        Some(wait) => Err(ApiError::TooManyRequests {
+            retry_after_seconds: wait.as_secs() + 1,
+        }),
+        None => Ok(()),
+    }
+}
+
+pub async fn record_throttled_failure(state: &AppState, username: &str, ip: &str) {
+    if let Err(err) = record_failure(&state.db, username, ip).await {
+        eprintln!("failed to record failed attempt: {err}");
+    }
+}
+
+fn check_new_password(new_password: &str, username: &str) -> Result<(), ApiError> {
+    validate_password(new_password, username).map_err(|message| {
+        ApiError::Validation(vec![FieldError {
+            field: "new_password",
+            message,
+        }])
+    })
+}
+
+pub async fn find_user(state: &AppState, user_id: Uuid) -> Result<User, ApiError> {
+    sqlx::query_as::<_, User>(
+        "SELECT id, username, password_hash, session_version, totp_enabled, display_name, created_at, updated_at FROM users WHERE id = $1",
+    )
+    .bind(user_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or(ApiError::NotFound("User not found"))
+}
+
+// Every token issued before this stops working, here and in the chat
+async fn set_password(
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_id: Uuid,
+    new_password: &str,
+) -> Result<i32, ApiError> {
+    let (session_version,): (i32,) = sqlx::query_as(
+        "UPDATE users SET password_hash = $2, session_version = session_version + 1, updated_at = NOW()
+         WHERE id = $1 RETURNING session_version",
+    )
+    .bind(user_id)
+    .bind(hash_password(new_password))
+    .fetch_one(&mut **transaction)
+    .await?;
+    Ok(session_version)
+}
+
+/// Change your password, signs out every other session and returns a fresh token for this one
+pub async fn change_password(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: AuthUser,
+    ApiJson(payload): ApiJson<ChangePasswordRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = find_user(&state, auth.id()).await?;
+    let throttle_username = username_key(&user.username);
+    let ip = client_ip(addr, &headers, state.trust_forwarded_for);
+    check_throttle(&state, &throttle_username, &ip).await?;
+
+    if !verify_password(&payload.current_password, &user.password_hash) {
+        record_throttled_failure(&state, &throttle_username, &ip).await;
+        return Err(ApiError::Unauthorized("Current password is incorrect"));
+    }
+    check_new_password(&payload.new_password, &user.username)?;
+    if payload.new_password == payload.current_password {
+        return Err(ApiError::Validation(vec![FieldError {
+            field: "new_password",
+            message: "New password must be different",
+        }]));
+    }
+
+    let mut transaction = state.db.begin().await?;
+    let session_version = set_password(&mut transaction, user.id, &payload.new_password).await?;
+    transaction.commit().await?;
+    if let Err(err) = record_success(&state.db, &throttle_username).await {
+        eprintln!("failed to clear failed attempts: {err}");
+    }
+    state.chat.revoke_sessions(user.id, session_version).await;
+
+    let device_id = auth.claims.device_id;
+    let user = User {
+        session_version,
+        ..user
+    };
+    Ok((StatusCode::OK, Json(login_response(user, device_id)?)))
+}
+
+/// Set a new password with one of the recovery codes from registration, each code works once
+pub async fn reset_password(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ApiJson(payload): ApiJson<ResetPasswordRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let throttle_username = username_key(&payload.username);
+    let ip = client_ip(addr, &headers, state.trust_forwarded_for);
+    check_throttle(&state, &throttle_username, &ip).await?;
+    check_new_password(&payload.new_password, &payload.username)?;
+
+    let user_id: Option<(Uuid,)> =
+        sqlx::query_as("SELECT id FROM users WHERE LOWER(username) = LOWER($1)")
+            .bind(&payload.username)
+            .fetch_optional(&state.db)
+            .await?;
+
+    // Same answer whether the user or the code is wrong
+    let mut transaction = state.db.begin().await?;
+    let user_id = match user_id {
+        Some((user_id,))
+            if use_recovery_code(&mut transaction, user_id, &payload.recovery_code).await? =>
+        {
+            user_id
+        }
+        _ => {
+            transaction.rollback().await?;
+            record_throttled_failure(&state, &throttle_username, &ip).await;
+            return Err(ApiError::Unauthorized("Invalid username or recovery code"));
+        }
+    };
+    let session_version = set_password(&mut transaction, user_id, &payload.new_password).await?;
+    transaction.commit().await?;
+    if let Err(err) = record_success(&state.db, &throttle_username).await {
+        eprintln!("failed to clear failed attempts: {err}");
+    }
+    state.chat.revoke_sessions(user_id, session_version).await;
+
+    Ok((
+        StatusCode::OK,
+        Json(ResetPasswordResponse {
+            remaining_recovery_codes: remaining_recovery_codes(&state.db, user_id).await?,
+        }),
+    ))
+}
+
+/// Replace all your recovery codes with new ones, for when they've run out or leaked
+pub async fn regenerate_recovery_codes(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: AuthUser,
+    ApiJson(payload): ApiJson<RegenerateRecoveryCodesRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = find_user(&state, auth.id()).await?;
+    let throttle_username = username_key(&user.username);
+    let ip = client_ip(addr, &headers, state.trust_forwarded_for);
+    check_throttle(&state, &throttle_username, &ip).await?;
+
+    if !verify_password(&payload.password, &user.password_hash) {
+        record_throttled_failure(&state, &throttle_username, &ip).await;
+        return Err(ApiError::Unauthorized("Password is incorrect"));
+    }
+
+    let mut transaction = state.db.begin().await?;
+    let recovery_codes = replace_recovery_codes(&mut transaction, user.id).await?;
+    transaction.commit().await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(RecoveryCodesResponse { recovery_codes }),
+    ))
+}
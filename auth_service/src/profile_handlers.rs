@@ -0,0 +1,188 @@
+use axum::{
+    Json,
+    body::Bytes,
+    extract::State,
+    http::{
+        HeaderValue, StatusCode,
+        header::{CACHE_CONTROL, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+    },
+    response::IntoResponse,
+};
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthUser,
+    directory_handlers::PUBLIC_USER_SELECT,
+    errors::ApiError,
+    extract::{ApiJson, ApiPath},
+    handlers::AppState,
+    models::{FieldError, PublicUser, UpdateProfileRequest},
+    validation::{validate_bio, validate_display_name},
+};
+
+// Avatars are shown small, anything bigger than this is a photo nobody resized
+const MAX_AVATAR_BYTES: usize = 256 * 1024;
+
+// Goes by the bytes rather than the Content-Type header, which is whatever the client says
+fn sniff_image_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        None
+    }
+}
+
+async fn find_profile(state: &AppState, user_id: Uuid) -> Result<PublicUser, ApiError> {
+    sqlx::query_as::<_, PublicUser>(&format!("{PUBLIC_USER_SELECT} WHERE u.id = $1"))
+        .bind(user_id)
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or(ApiError::NotFound("User not found"))
+}
+
+// Trimmed, with an empty string meaning the field should be cleared
+fn profile_field(
+    value: Option<String>,
+    current: Option<String>,
+    field: &'static str,
+    validate: fn(&str) -> Result<(), &'static str>,
+    errors: &mut Vec<FieldError>,
+) -> Option<String> {
+    let Some(value) = value else {
+        return current;
+    };
+    let value = value.trim();
+    if value.is_empty() {
+        return None;
+    }
+    if let Err(message) = validate(value) {
+        errors.push(FieldError { field, message });
+    }
+    Some(value.to_string())
+}
+
+/// Your own profile
+pub async fn get_me(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    Ok((StatusCode::OK, Json(find_profile(&state, auth.id()).await?)))
+}
+
+/// Update your display name and/or bio, the chat picks up a new display name straight away
+pub async fn update_me(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    ApiJson(payload): ApiJson<UpdateProfileRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let profile = find_profile(&state, auth.id()).await?;
+
+    let mut errors: Vec<FieldError> = vec![];
+    let display_name = profile_field(
+        payload.display_name,
+        profile.display_name.clone(),
+        "display_name",
+        validate_display_name,
+        &mut errors,
+    );
+    let bio = profile_field(payload.bio, profile.bio, "bio", validate_bio, &mut errors);
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+
+    sqlx::query("UPDATE users SET display_name = $2, bio = $3, updated_at = NOW() WHERE id = $1")
+        .bind(profile.id)
+        .bind(&display_name)
+        .bind(&bio)
+        .execute(&state.db)
+        .await?;
+    if display_name != profile.display_name {
+        state
+            .chat
+            .profile_updated(profile.id, &profile.username, display_name.as_deref())
+            .await;
+    }
+
+    Ok((
+        StatusCode::OK,
+        Json(find_profile(&state, profile.id).await?),
+    ))
+}
+
+/// Replace your avatar, the body is the image itself (PNG, JPEG, GIF or WebP)
+pub async fn upload_avatar(
+    State(state): State<AppState>,
+    auth: AuthUser,
+    body: Bytes,
+) -> Result<impl IntoResponse, ApiError> {
+    if body.len() > MAX_AVATAR_BYTES {
+        return Err(ApiError::PayloadTooLarge("Avatar must be at most 256 KiB"));
+    }
+    let Some(content_type) = sniff_image_type(&body) else {
+        return Err(ApiError::UnsupportedMediaType(
+            "Avatar must be a PNG, JPEG, GIF or WebP image",
+        ));
+    };
+
+    sqlx::query(
+        "INSERT INTO user_avatars (user_id, content_type, data) VALUES ($1, $2, $3)
+         ON CONFLICT (user_id) DO UPDATE SET
+            content_type = EXCLUDED.content_type,
+            data = EXCLUDED.data,
+            updated_at = NOW()",
+    )
+    .bind(auth.id())
+    .bind(content_type)
+    .bind(body.as_ref())
+    .execute(&state.db)
+    .await?;
+
+    Ok((StatusCode::OK, Json(find_profile(&state, auth.id()).await?)))
+}
+
+/// Remove your avatar
+pub async fn delete_avatar(
+    State(state): State<AppState>,
+    auth: AuthUser,
+) -> Result<impl IntoResponse, ApiError> {
+    sqlx::query("DELETE FROM user_avatars WHERE user_id = $1")
+        .bind(auth.id())
+        .execute(&state.db)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Someone's avatar image, no token needed so it works in an <img> tag
+pub async fn get_avatar(
+    State(state): State<AppState>,
+    ApiPath(user_id): ApiPath<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    let (content_type, data): (String, Vec<u8>) =
+        sqlx::query_as("SELECT content_type, data FROM user_avatars WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_optional(&state.db)
+            .await?
+            .ok_or(ApiError::NotFound("No avatar"))?;
+
+    let content_type = HeaderValue::from_str(&content_type)
+        .map_err(|e| ApiError::Internal(format!("bad stored content type: {e}")))?;
+    Ok((
+        StatusCode::OK,
+        [
+            (CONTENT_TYPE, content_type),
+            // avatar_url changes with the avatar, so the old one can be cached for good
+            (
+                CACHE_CONTROL,
+                HeaderValue::from_static("public, max-age=31536000, immutable"),
+            ),
+            (X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")),
+        ],
+        data,
+    ))
+}
@@ -0,0 +1,84 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+use crate::db::DbPool;
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+const CODE_CHARS: usize = 16; // 80 bits
+// Crockford's base32, no I, L, O or U to mix up when copying them down
+const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+// Shown as XXXX-XXXX-XXXX-XXXX
+fn generate_code() -> String {
+    let mut bytes = [0u8; CODE_CHARS];
+    OsRng.fill_bytes(&mut bytes);
+    let chars: Vec<char> = bytes
+        .iter()
+        .map(|b| ALPHABET[(*b as usize) % ALPHABET.len()] as char)
+        .collect();
+    chars
+        .chunks(4)
+        .map(|c| c.iter().collect::<String>())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// Dashes, spaces and case don't matter when typing one back in
+fn hash_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    format!("{:x}", Sha256::digest(normalized.as_bytes()))
+}
+
+// Replaces any codes the user already had, returns the new ones in plain text for the only time
+pub async fn replace_recovery_codes(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+) -> Result<Vec<String>, sqlx::Error> {
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = $1")
+        .bind(user_id)
+        .execute(&mut **transaction)
+        .await?;
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| generate_code()).collect();
+    for code in &codes {
+        sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES ($1, $2)")
+            .bind(user_id)
+            .bind(hash_code(code))
+            .execute(&mut **transaction)
+            .await?;
+    }
+    Ok(codes)
+}
+
+// Marks the code used if it's valid, so it can't be used twice even with concurrent requests
+pub async fn use_recovery_code(
+    transaction: &mut Transaction<'_, Postgres>,
+    user_id: Uuid,
+    code: &str,
+) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query(
+        "UPDATE recovery_codes SET used_at = NOW()
+         WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL",
+    )
+    .bind(user_id)
+    .bind(hash_code(code))
+    .execute(&mut **transaction)
+    .await?;
+    Ok(res.rows_affected() == 1)
+}
+
+pub async fn remaining_recovery_codes(db: &DbPool, user_id: Uuid) -> Result<i64, sqlx::Error> {
+    let (remaining,): (i64,) = sqlx::query_as(
+        "SELECT COUNT(*) FROM recovery_codes WHERE user_id = $1 AND used_at IS NULL",
+    )
+    .bind(user_id)
+    .fetch_one(db)
+    .await?;
+    Ok(remaining)
+}
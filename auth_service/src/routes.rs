@@ -1,10 +1,19 @@
 use axum::{
     Router,
-    routing::{get, post},
+    routing::{get, post, put},
 };
 use tower_http::cors::{Any, CorsLayer};
 
-use crate::handlers::{AppState, login, register, root, verify};
+use crate::{
+    account_handlers::delete_account,
+    admin_handlers::unlock_login,
+    directory_handlers::{get_user, get_user_by_username, search_users},
+    handlers::{AppState, login, register, root, verify},
+    key_handlers::{get_prekey_bundle, list_user_devices, upload_device},
+    password_handlers::{change_password, regenerate_recovery_codes, reset_password},
+    profile_handlers::{delete_avatar, get_avatar, get_me, update_me, upload_avatar},
+    totp_handlers::{confirm_totp, disable_totp, login_mfa, setup_totp},
+};
 
 pub fn create_router(state: AppState) -> Router {
     let cors = CorsLayer::new()
@@ -16,7 +25,29 @@ pub fn create_router(state: AppState) -> Router {
         .route("/", get(root))
         .route("/register", post(register))
         .route("/login", post(login))
+        .route("/login/mfa", post(login_mfa))
         .route("/verify", post(verify))
+        .route("/password/reset", post(reset_password))
+        .route(
+            "/users/me",
+            get(get_me).patch(update_me).delete(delete_account),
+        )
+        .route("/users/me/avatar", put(upload_avatar).delete(delete_avatar))
+        .route("/users/{user_id}", get(get_user))
+        .route("/users/{user_id}/avatar", get(get_avatar))
+        .route("/users/search", get(search_users))
+        .route("/users/by_username/{username}", get(get_user_by_username))
+        .route("/users/me/password", post(change_password))
+        .route("/users/me/recovery_codes", post(regenerate_recovery_codes))
+        .route("/users/me/totp/setup", post(setup_totp))
+        .route("/users/me/totp/confirm", post(confirm_totp))
+        .route("/users/me/totp/disable", post(disable_totp))
+        .route(
+            "/users/{user_id}/devices",
+            post(upload_device).get(list_user_devices),
+        )
+        .route("/users/{user_id}/prekey_bundle", get(get_prekey_bundle))
+        .route("/admin/unlock", post(unlock_login))
         .layer(cors)
         .with_state(state)
 }
@@ -0,0 +1,204 @@
+use std::{net::SocketAddr, time::Duration};
+
+use axum::http::HeaderMap;
+use chrono::{DateTime, Utc};
+
+use crate::db::DbPool;
+
+// A few typos are free, after that every failure doubles the lockout
+const FREE_USERNAME_FAILURES: i32 = 3;
+// One IP can sit in front of a lot of people (offices, NAT...) so it gets more leeway
+const FREE_IP_FAILURES: i32 = 20;
+const MAX_LOCKOUT_SECONDS: f64 = 15.0 * 60.0;
+// Failures older than this don't count anymore
+const FAILURE_WINDOW_SECONDS: f64 = 60.0 * 60.0;
+
+#[derive(Clone, Copy)]
+pub enum ThrottleKind {
+    Username,
+    Ip,
+}
+
+impl ThrottleKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ThrottleKind::Username => "username",
+            ThrottleKind::Ip => "ip",
+        }
+    }
+
+    fn free_failures(&self) -> i32 {
+        match self {
+            ThrottleKind::Username => FREE_USERNAME_FAILURES,
+            ThrottleKind::Ip => FREE_IP_FAILURES,
+        }
+    }
+}
+
+// Usernames are throttled regardless of case, so Alice and alice share attempts
+pub fn username_key(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+// Only trust X-Forwarded-For when we're told we're behind a proxy, anyone can send it otherwise
+pub fn client_ip(addr: SocketAddr, headers: &HeaderMap, trust_forwarded_for: bool) -> String {
+    if trust_forwarded_for
+        && let Some(ip) = headers
+            .get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.split(',').next())
+            .map(|v| v.trim())
+            .filter(|v| !v.is_empty())
+    {
+        return ip.to_string();
+    }
+    addr.ip().to_string()
+}
+
+// How long until a login may be tried again, None if it can go ahead now
+pub async fn locked_for(
+    db: &DbPool,
+    username: &str,
+    ip: &str,
+) -> Result<Option<Duration>, sqlx::Error> {
+    let locked_until: Option<(DateTime<Utc>,)> = sqlx::query_as(
+        "SELECT MAX(locked_until) FROM login_throttles
+         WHERE ((kind = 'username' AND key = $1) OR (kind = 'ip' AND key = $2))
+            AND locked_until > NOW()
+         HAVING MAX(locked_until) IS NOT NULL",
+    )
+    .bind(username)
+    .bind(ip)
+    .fetch_optional(db)
+    .await?;
+
+    Ok(locked_until.and_then(|(until,)| (until - Utc::now()).to_std().ok()))
+}
+
+pub async fn record_failure(db: &DbPool, username: &str, ip: &str) -> Result<(), sqlx::Error> {
+    record_key_failure(db, ThrottleKind::Username, username).await?;
+    record_key_failure(db, ThrottleKind::Ip, ip).await
+}
+
+// 1s, 2s, 4s... capped, so a real user who fat-fingers it a few more times isn't out for long
+fn lockout_seconds(over: i32) -> f64 {
+    2f64.powi((over - 1).min(30)).min(MAX_LOCKOUT_SECONDS)
+}
+
+// Every lockout up to the cap, anything further along the query just takes the last one
+fn lockout_schedule() -> Vec<f64> {
+    let mut schedule = vec![];
+    for over in 1.. {
+        let lockout = lockout_seconds(over);
+        schedule.push(lockout);
+        if lockout >= MAX_LOCKOUT_SECONDS {
+            break;
+        }
+    }
+    schedule
+}
+
+// One statement, so concurrent failures can't both read the old count and lock too briefly
+async fn record_key_failure(db: &DbPool, kind: ThrottleKind, key: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO login_throttles (kind, key, failures, last_failure_at) VALUES ($1, $2, 1, NOW())
+         ON CONFLICT (kind, key) DO UPDATE SET (failures, last_failure_at, locked_until) = (
+            SELECT n.failures, NOW(), CASE
+                WHEN n.failures > $4 THEN NOW() + make_interval(
+                    secs => ($5::float8[])[LEAST(n.failures - $4, cardinality($5::float8[]))]
+                )
+                ELSE login_throttles.locked_until
+            END
+            FROM (SELECT CASE
+                WHEN login_throttles.last_failure_at < NOW() - make_interval(secs => $3) THEN 1
+                ELSE login_throttles.failures + 1
+            END AS failures) n
+         )",
+    )
+    .bind(kind.as_str())
+    .bind(key)
+    .bind(FAILURE_WINDOW_SECONDS)
+    .bind(kind.free_failures())
+    .bind(lockout_schedule())
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+// Only the username is cleared, otherwise logging into your own account would reset an IP that's guessing at others
+pub async fn record_success(db: &DbPool, username: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM login_throttles WHERE kind = 'username' AND key = $1")
+        .bind(username)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+// Returns whether there was anything to unlock
+pub async fn unlock(db: &DbPool, kind: ThrottleKind, key: &str) -> Result<bool, sqlx::Error> {
+    let res = sqlx::query("DELETE FROM login_throttles WHERE kind = $1 AND key = $2")
+        .bind(kind.as_str())
+        .bind(key)
+        .execute(db)
+        .await?;
+    Ok(res.rows_affected() > 0)
+}
+
+// Old rows would otherwise pile up forever, one per username or IP that ever got a password wrong
+pub async fn prune(db: &DbPool) -> Result<u64, sqlx::Error> {
+    let res = sqlx::query(
+        "DELETE FROM login_throttles
+         WHERE last_failure_at < NOW() - make_interval(secs => $1)
+            AND (locked_until IS NULL OR locked_until < NOW())",
+    )
+    .bind(FAILURE_WINDOW_SECONDS)
+    .execute(db)
+    .await?;
+    Ok(res.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lockout_doubles_from_one_second() {
+        assert_eq!(lockout_seconds(1), 1.0);
+        assert_eq!(lockout_seconds(2), 2.0);
+        assert_eq!(lockout_seconds(3), 4.0);
+        assert_eq!(lockout_seconds(10), 512.0);
+    }
+
+    #[test]
+    fn lockout_is_capped() {
+        assert_eq!(lockout_seconds(11), MAX_LOCKOUT_SECONDS);
+        // Way past where 2^n would overflow
+        assert_eq!(lockout_seconds(10_000), MAX_LOCKOUT_SECONDS);
+    }
+
+    #[test]
+    fn schedule_runs_up_to_the_cap() {
+        let schedule = lockout_schedule();
+        assert_eq!(schedule.first(), Some(&1.0));
+        assert_eq!(schedule.last(), Some(&MAX_LOCKOUT_SECONDS));
+        assert!(schedule.windows(2).all(|w| w[0] < w[1]));
+        for (i, lockout) in schedule.iter().enumerate() {
+            assert_eq!(*lockout, lockout_seconds(i as i32 + 1));
+        }
+    }
+
+    #[test]
+    fn usernames_share_a_key_regardless_of_case() {
+        assert_eq!(username_key(" Alice "), username_key("alice"));
+    }
+
+    #[test]
+    fn forwarded_for_only_when_trusted() {
+        let addr: SocketAddr = "10.0.0.1:1234".parse().unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.7, 10.0.0.2".parse().unwrap());
+        assert_eq!(client_ip(addr, &headers, true), "203.0.113.7");
+        assert_eq!(client_ip(addr, &headers, false), "10.0.0.1");
+        assert_eq!(client_ip(addr, &HeaderMap::new(), true), "10.0.0.1");
+    }
+}
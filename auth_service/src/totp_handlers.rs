@@ -0,0 +1,244 @@
+use std::net::SocketAddr;
+
+use axum::{
+    Json,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+
+use crate::{
+    auth::AuthUser,
+    errors::ApiError,
+    extract::ApiJson,
+    handlers::{AppState, login_response, verify_password},
+    jwt::decode_mfa_challenge,
+    models::{
+        MfaLoginRequest, TotpCodeRequest, TotpDisableRequest, TotpSetupRequest, TotpSetupResponse,
+        TotpStatusResponse, User,
+    },
+    password_handlers::{check_throttle, find_user, record_throttled_failure},
+    recovery::use_recovery_code,
+    throttle::{client_ip, record_success, username_key},
+    totp::{disable, encode_secret, generate_secret, load_secret, otpauth_uri, verify_and_consume},
+};
+
+pub enum SecondFactor {
+    Totp,
+    RecoveryCode,
+}
+
+// Either a code from the app or one of the recovery codes, None if it was wrong
+pub async fn verify_second_factor(
+    state: &AppState,
+    transaction: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user: &User,
+    code: Option<&str>,
+    recovery_code: Option<&str>,
+) -> Result<Option<SecondFactor>, ApiError> {
+    match (code, recovery_code) {
+        (Some(code), None) => {
+            let Some(secret) = load_secret(&state.db, user.id).await? else {
+                return Ok(None);
+            };
+            let valid = user.totp_enabled
+                && verify_and_consume(&mut **transaction, user.id, &secret, code).await?;
+            Ok(valid.then_some(SecondFactor::Totp))
+        }
+        (None, Some(recovery_code)) => {
+            let valid = use_recovery_code(transaction, user.id, recovery_code).await?;
+            Ok(valid.then_some(SecondFactor::RecoveryCode))
+        }
+        _ => Err(ApiError::BadRequest(
+            "Send exactly one of code or recovery_code",
+        )),
+    }
+}
+
+/// Second step of logging in when TOTP is on, trade the challenge token and a code for a session token
+pub async fn login_mfa(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    ApiJson(payload): ApiJson<MfaLoginRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let challenge = decode_mfa_challenge(&payload.challenge_token)
+        .ok_or(ApiError::Unauthorized("Invalid or expired challenge"))?;
+    let user = find_user(&state, challenge.sub).await?;
+    // A password change in the meantime kills the challenge too
+    if user.session_version != challenge.sv {
+        return Err(ApiError::Unauthorized("Invalid or expired challenge"));
+    }
+
+    let throttle_username = username_key(&user.username);
+    let ip = client_ip(addr, &headers, state.trust_forwarded_for);
+    check_throttle(&state, &throttle_username, &ip).await?;
+
+    let mut transaction = state.db.begin().await?;
+    let factor = verify_second_factor(
+        &state,
+        &mut transaction,
+        &user,
+        payload.code.as_deref(),
+        payload.recovery_code.as_deref(),
+    )
+    .await?;
+    match factor {
+        Some(SecondFactor::Totp) => {}
+        // Lost the phone, so the recovery code gets you in and TOTP is off until you set it up again
+        Some(SecondFactor::RecoveryCode) => disable(&mut *transaction, user.id).await?,
+        None => {
+            transaction.rollback().await?;
+            record_throttled_failure(&state, &throttle_username, &ip).await;
+            return Err(ApiError::Unauthorized("Invalid code"));
+        }
+    }
+    transaction.commit().await?;
+    if let Err(err) = record_success(&state.db, &throttle_username).await {
+        eprintln!("failed to clear failed attempts: {err}");
+    }
+
+    Ok((
+        StatusCode::OK,
+        Json(login_response(user, challenge.device_id)?),
+    ))
+}
+
+/// Start TOTP enrollment, returns the secret to put in an authenticator app (not on until confirmed)
+pub async fn setup_totp(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: AuthUser,
+    ApiJson(payload): ApiJson<TotpSetupRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = find_user(&state, auth.id()).await?;
+    let throttle_username = username_key(&user.username);
+    let ip = client_ip(addr, &headers, state.trust_forwarded_for);
+    check_throttle(&state, &throttle_username, &ip).await?;
+
+    if !verify_password(&payload.password, &user.password_hash) {
+        record_throttled_failure(&state, &throttle_username, &ip).await;
+        return Err(ApiError::Unauthorized("Password is incorrect"));
+    }
+    if user.totp_enabled {
+        return Err(ApiError::Conflict(
+            "Two-factor authentication is already enabled",
+        ));
+    }
+
+    // Starting over just replaces a secret that was never confirmed
+    let secret = generate_secret();
+    let res = sqlx::query(
+        "UPDATE users SET totp_secret = $2, totp_last_step = NULL, updated_at = NOW()
+         WHERE id = $1 AND NOT totp_enabled",
+    )
+    .bind(user.id)
+    .bind(&secret)
+    .execute(&state.db)
+    .await?;
+    if res.rows_affected() == 0 {
+        return Err(ApiError::Conflict(
+            "Two-factor authentication is already enabled",
+        ));
+    }
+
+    Ok((
+        StatusCode::OK,
+        Json(TotpSetupResponse {
+            secret: encode_secret(&secret),
+            otpauth_uri: otpauth_uri(&secret, &user.username),
+        }),
+    ))
+}
+
+/// Finish TOTP enrollment with the first code from the app, logins need a code from then on
+pub async fn confirm_totp(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: AuthUser,
+    ApiJson(payload): ApiJson<TotpCodeRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = find_user(&state, auth.id()).await?;
+    let throttle_username = username_key(&user.username);
+    let ip = client_ip(addr, &headers, state.trust_forwarded_for);
+    check_throttle(&state, &throttle_username, &ip).await?;
+
+    // Locked until enabled, otherwise a setup_totp in between could swap the secret for one
+    // nobody has confirmed and we'd switch that on instead
+    let mut transaction = state.db.begin().await?;
+    let (secret, totp_enabled): (Option<Vec<u8>>, bool) =
+        sqlx::query_as("SELECT totp_secret, totp_enabled FROM users WHERE id = $1 FOR UPDATE")
+            .bind(user.id)
+            .fetch_one(&mut *transaction)
+            .await?;
+    if totp_enabled {
+        return Err(ApiError::Conflict(
+            "Two-factor authentication is already enabled",
+        ));
+    }
+    let Some(secret) = secret else {
+        return Err(ApiError::Conflict("Two-factor setup hasn't been started"));
+    };
+
+    if !verify_and_consume(&mut *transaction, user.id, &secret, &payload.code).await? {
+        transaction.rollback().await?;
+        record_throttled_failure(&state, &throttle_username, &ip).await;
+        return Err(ApiError::Unauthorized("Invalid code"));
+    }
+    sqlx::query("UPDATE users SET totp_enabled = TRUE, updated_at = NOW() WHERE id = $1")
+        .bind(user.id)
+        .execute(&mut *transaction)
+        .await?;
+    transaction.commit().await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(TotpStatusResponse { totp_enabled: true }),
+    ))
+}
+
+/// Turn TOTP off, takes a current code or one of the recovery codes
+pub async fn disable_totp(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    auth: AuthUser,
+    ApiJson(payload): ApiJson<TotpDisableRequest>,
+) -> Result<impl IntoResponse, ApiError> {
+    let user = find_user(&state, auth.id()).await?;
+    if !user.totp_enabled {
+        return Err(ApiError::Conflict(
+            "Two-factor authentication isn't enabled",
+        ));
+    }
+
+    let throttle_username = username_key(&user.username);
+    let ip = client_ip(addr, &headers, state.trust_forwarded_for);
+    check_throttle(&state, &throttle_username, &ip).await?;
+
+    let mut transaction = state.db.begin().await?;
+    let factor = verify_second_factor(
+        &state,
+        &mut transaction,
+        &user,
+        payload.code.as_deref(),
+        payload.recovery_code.as_deref(),
+    )
+    .await?;
+    if factor.is_none() {
+        transaction.rollback().await?;
+        record_throttled_failure(&state, &throttle_username, &ip).await;
+        return Err(ApiError::Unauthorized("Invalid code"));
+    }
+    disable(&mut *transaction, user.id).await?;
+    transaction.commit().await?;
+
+    Ok((
+        StatusCode::OK,
+        Json(TotpStatusResponse {
+            totp_enabled: false,
+        }),
+    ))
+}
@@ -0,0 +1,263 @@
+use unicode_properties::{GeneralCategory, UnicodeGeneralCategory};
+
+use crate::models::FieldError;
+
+const MIN_USERNAME_CHARS: usize = 3;
+const MAX_USERNAME_CHARS: usize = 24; // users.username is a VARCHAR(24)
+const MIN_PASSWORD_CHARS: usize = 10;
+const MAX_PASSWORD_CHARS: usize = 128; // Argon2 doesn't care, but nobody needs more
+const MAX_DISPLAY_NAME_CHARS: usize = 50;
+const MAX_BIO_CHARS: usize = 300;
+// What socket_server shows in place of a deleted account, nobody else gets to look like one
+const DELETED_USER_NAME: &str = "Deleted user";
+
+// Names that would look like they speak for the service, plus ones the chat uses itself
+const RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "root",
+    "system",
+    "support",
+    "help",
+    "moderator",
+    "staff",
+    "official",
+    "security",
+    "api",
+    "deleted",
+    "deleted_user",
+    "everyone",
+    "here",
+    "null",
+    "undefined",
+];
+
+// Not exhaustive, just the ones everyone tries first
+const COMMON_PASSWORDS: &[&str] = &[
+    "password",
+    "password1",
+    "password123",
+    "1234567890",
+    "0123456789",
+    "12345678910",
+    "qwertyuiop",
+    "iloveyou123",
+    "letmein123",
+    "welcome123",
+    "passw0rd123",
+    "abcdefghij",
+    "aaaaaaaaaa",
+];
+
+pub fn validate_username(username: &str) -> Result<(), &'static str> {
+    let length = username.chars().count();
+    if length < MIN_USERNAME_CHARS {
+        return Err("Username must be at least 3 characters");
+    }
+    if length > MAX_USERNAME_CHARS {
+        return Err("Username must be at most 24 characters");
+    }
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err("Username can only contain letters, numbers and underscores");
+    }
+    if !username.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        return Err("Username must start with a letter");
+    }
+    if RESERVED_USERNAMES.contains(&username.to_lowercase().as_str()) {
+        return Err("Username is reserved");
+    }
+    Ok(())
+}
+
+pub fn validate_password(password: &str, username: &str) -> Result<(), &'static str> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_CHARS {
+        return Err("Password must be at least 10 characters");
+    }
+    if length > MAX_PASSWORD_CHARS {
+        return Err("Password must be at most 128 characters");
+    }
+    let lowercase = password.to_lowercase();
+    if COMMON_PASSWORDS.contains(&lowercase.as_str()) {
+        return Err("Password is too common");
+    }
+    if !username.is_empty() && lowercase.contains(&username.to_lowercase()) {
+        return Err("Password can't contain your username");
+    }
+    // Length alone lets "aaaaaaaaaaaa" through
+    let mut distinct: Vec<char> = password.chars().collect();
+    distinct.sort_unstable();
+    distinct.dedup();
+    if distinct.len() < 4 {
+        return Err("Password needs more variety");
+    }
+    Ok(())
+}
+
+// Every problem at once, so the form can show them all in one go
+pub fn validate_registration(username: &str, password: &str) -> Vec<FieldError> {
+    let mut errors = vec![];
+    if let Err(message) = validate_username(username) {
+        errors.push(FieldError {
+            field: "username",
+            message,
+        });
+    }
+    if let Err(message) = validate_password(password, username) {
+        errors.push(FieldError {
+            field: "password",
+            message,
+        });
+    }
+    errors
+}
+
+// Already trimmed, anything goes except characters that could break up chat layouts or hide what's written,
+// and the name socket_server shows for deleted accounts
+pub fn validate_display_name(display_name: &str) -> Result<(), &'static str> {
+    if display_name.chars().count() > MAX_DISPLAY_NAME_CHARS {
+        return Err("Display name must be at most 50 characters");
+    }
+    if display_name.chars().any(char::is_control) {
+        return Err("Display name can't contain control characters");
+    }
+    // Bidi overrides, zero width spaces and joiners and the like, they make names look like something else
+    if display_name
+        .chars()
+        .any(|c| c.general_category() == GeneralCategory::Format)
+    {
+        return Err("Display name can't contain invisible formatting characters");
+    }
+    let collapsed = display_name
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    if collapsed.to_lowercase() == DELETED_USER_NAME.to_lowercase() {
+        return Err("That display name is reserved");
+    }
+    Ok(())
+}
+
+pub fn validate_bio(bio: &str) -> Result<(), &'static str> {
+    if bio.chars().count() > MAX_BIO_CHARS {
+        return Err("Bio must be at most 300 characters");
+    }
+    if bio.chars().any(|c| c.is_control() && c != '\n') {
+        return Err("Bio can't contain control characters other than new lines");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn username_length_limits() {
+        assert!(validate_username("ab").is_err());
+        assert!(validate_username("abc").is_ok());
+        assert!(validate_username(&"a".repeat(24)).is_ok());
+        assert!(validate_username(&"a".repeat(25)).is_err());
+    }
+
+    #[test]
+    fn username_characters() {
+        assert!(validate_username("alice_99").is_ok());
+        assert!(validate_username("alice-99").is_err());
+        assert!(validate_username("alice 99").is_err());
+        // Letters that look ASCII but aren't
+        assert!(validate_username("аlice").is_err());
+        assert!(validate_username("9lives").is_err());
+        assert!(validate_username("_alice").is_err());
+    }
+
+    #[test]
+    fn reserved_usernames_regardless_of_case() {
+        assert_eq!(validate_username("Admin"), Err("Username is reserved"));
+        assert_eq!(
+            validate_username("deleted_user"),
+            Err("Username is reserved")
+        );
+        assert!(validate_username("admins").is_ok());
+    }
+
+    #[test]
+    fn password_length_counts_characters() {
+        assert!(validate_password("Sh0rt!pw", "alice").is_err());
+        assert!(validate_password("Sup3rSecret!pw", "alice").is_ok());
+        assert!(validate_password(&"aB3!".repeat(33), "alice").is_err());
+        // Ten characters, more than ten bytes
+        assert!(validate_password("pässwörd1!", "alice").is_ok());
+    }
+
+    #[test]
+    fn common_passwords_regardless_of_case() {
+        assert_eq!(
+            validate_password("Password123", "alice"),
+            Err("Password is too common")
+        );
+    }
+
+    #[test]
+    fn password_cant_contain_the_username() {
+        assert_eq!(
+            validate_password("xx-ALICE-secret", "alice"),
+            Err("Password can't contain your username")
+        );
+        // Nothing to compare to when there's no username
+        assert!(validate_password("xx-alice-secret", "").is_ok());
+    }
+
+    #[test]
+    fn password_needs_variety() {
+        assert_eq!(
+            validate_password("abababababab", "alice"),
+            Err("Password needs more variety")
+        );
+        assert!(validate_password("abcdabcdabcd", "alice").is_ok());
+    }
+
+    #[test]
+    fn registration_reports_every_field() {
+        let errors = validate_registration("a", "short");
+        let fields: Vec<_> = errors.iter().map(|e| e.field).collect();
+        assert_eq!(fields, ["username", "password"]);
+        assert!(validate_registration("alice", "Sup3rSecret!pw").is_empty());
+    }
+
+    #[test]
+    fn display_name_length_counts_characters() {
+        assert!(validate_display_name(&"é".repeat(50)).is_ok());
+        assert!(validate_display_name(&"é".repeat(51)).is_err());
+    }
+
+    #[test]
+    fn display_name_rejects_control_and_invisible_characters() {
+        assert!(validate_display_name("Alice Smith").is_ok());
+        assert!(validate_display_name("Alice\nSmith").is_err());
+        // Right-to-left override, zero width space, zero width joiner
+        assert!(validate_display_name("Alice\u{202E}htimS").is_err());
+        assert!(validate_display_name("Ali\u{200B}ce").is_err());
+        assert!(validate_display_name("Ali\u{200D}ce").is_err());
+        assert!(validate_display_name("Zoë 🦀").is_ok());
+    }
+
+    #[test]
+    fn display_name_cant_pose_as_a_deleted_user() {
+        assert_eq!(
+            validate_display_name("deleted   USER"),
+            Err("That display name is reserved")
+        );
+        assert!(validate_display_name("Deleted users").is_ok());
+    }
+
+    #[test]
+    fn bio_allows_new_lines_only() {
+        assert!(validate_bio("Line one\nLine two").is_ok());
+        assert!(validate_bio("Tab\there").is_err());
+        assert!(validate_bio(&"x".repeat(301)).is_err());
+    }
+}
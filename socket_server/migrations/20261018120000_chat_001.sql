@@ -0,0 +1,29 @@
+-- Conversations: who is in them and their settings
+CREATE TABLE conversations (
+    id UUID PRIMARY KEY,
+    -- Disappearing messages, NULL keeps them forever
+    message_ttl_seconds BIGINT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE conversation_participants (
+    conversation_id UUID NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    -- users.id from auth_service, different database so no foreign key
+    user_id UUID NOT NULL,
+
+    PRIMARY KEY (conversation_id, user_id)
+);
+
+CREATE INDEX idx_conversation_participants_user_id ON conversation_participants(user_id);
+
+-- Messages waiting for their send_at, removed once they've been sent or cancelled
+CREATE TABLE scheduled_messages (
+    id UUID PRIMARY KEY,
+    conversation_id UUID NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    sender_id UUID NOT NULL,
+    message TEXT NOT NULL,
+    send_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_scheduled_messages_sender_id ON scheduled_messages(sender_id);
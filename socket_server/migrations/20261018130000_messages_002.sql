@@ -0,0 +1,29 @@
+-- Messages as the router keeps them, the columns are only what we need to query on
+CREATE TABLE messages (
+    id BIGINT PRIMARY KEY,
+    conversation_id UUID NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    sender_id UUID NOT NULL,
+    body TEXT NOT NULL,
+    sent_at TIMESTAMPTZ NOT NULL,
+    deleted_at TIMESTAMPTZ,
+    expires_at TIMESTAMPTZ,
+
+    -- Everything else (edits, reactions, mentions, attachments...) straight from the router
+    data JSONB NOT NULL,
+
+    -- 'simple' rather than 'english', we have plenty of people not writing in English
+    search TSVECTOR GENERATED ALWAYS AS (to_tsvector('simple', body)) STORED
+);
+
+CREATE INDEX idx_messages_conversation_id ON messages(conversation_id, id);
+CREATE INDEX idx_messages_search ON messages USING GIN(search);
+
+-- How far each participant has got through a conversation
+CREATE TABLE conversation_receipts (
+    conversation_id UUID NOT NULL REFERENCES conversations(id) ON DELETE CASCADE,
+    user_id UUID NOT NULL,
+    delivered_up_to BIGINT NOT NULL DEFAULT 0,
+    read_up_to BIGINT NOT NULL DEFAULT 0,
+
+    PRIMARY KEY (conversation_id, user_id)
+);
@@ -0,0 +1,5 @@
+-- Accounts deleted in auth_service, their tokens are refused even after a restart
+CREATE TABLE deleted_users (
+    user_id UUID PRIMARY KEY,
+    deleted_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
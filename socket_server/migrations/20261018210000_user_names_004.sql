@@ -0,0 +1,6 @@
+-- What messages call each sender, so names survive a restart without every sender reconnecting
+CREATE TABLE user_names (
+    user_id UUID PRIMARY KEY,
+    name TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
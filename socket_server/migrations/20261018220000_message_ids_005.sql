@@ -0,0 +1,8 @@
+-- The highest message id ever handed out, expired messages get their rows deleted
+-- so MAX(messages.id) can go backwards and ids would be reused after a restart
+CREATE TABLE message_id_high_water (
+    singleton BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (singleton),
+    last_id BIGINT NOT NULL
+);
+
+INSERT INTO message_id_high_water (last_id) SELECT COALESCE(MAX(id), 0) FROM messages;
@@ -0,0 +1,133 @@
+use std::{
+    collections::HashSet,
+    env,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+pub const DEFAULT_MAX_ATTACHMENT_BYTES: usize = 10 * 1024 * 1024;
+pub const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+// Uploads that never get sent with a Say are forgotten after this, and their bytes go with them
+pub const PENDING_ATTACHMENT_TTL: chrono::Duration = chrono::Duration::hours(24);
+// A blob written this recently may belong to an upload the router hasn't heard about yet
+const BLOB_GRACE: Duration = Duration::from_secs(10 * 60);
+
+// Anything else is refused at upload, we don't want to be a general file host
+pub const ALLOWED_MIME_TYPES: &[&str] = &[
+    "image/png",
+    "image/jpeg",
+    "image/gif",
+    "image/webp",
+    "application/pdf",
+    "text/plain",
+];
+
+// What the bytes actually are, for checking against the type the upload claims
+// text/plain has no magic number, so it's anything that's valid UTF-8 without NULs
+pub fn sniff_mime_type(data: &[u8]) -> Option<&'static str> {
+    if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        Some("image/jpeg")
+    } else if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if data.len() >= 12 && &data[..4] == b"RIFF" && &data[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else if data.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else if std::str::from_utf8(data).is_ok_and(|text| !text.contains('\0')) {
+        Some("text/plain")
+    } else {
+        None
+    }
+}
+
+pub fn attachment_dir() -> PathBuf {
+    PathBuf::from(env::var("ATTACHMENT_DIR").unwrap_or("attachments".to_string()))
+}
+
+// Deletes every blob (and leftover .tmp file) that no attachment points at anymore
+pub async fn remove_unreferenced_blobs(dir: &Path, referenced: &HashSet<String>) {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("failed to list attachment dir: {e}");
+            return;
+        }
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if referenced.contains(&name) {
+            continue;
+        }
+        // Uploads of bytes we already have bump the blob's mtime, so they're safe here too
+        let recent = entry
+            .metadata()
+            .await
+            .and_then(|m| m.modified())
+            .map(|modified| {
+                SystemTime::now()
+                    .duration_since(modified)
+                    .is_ok_and(|age| age < BLOB_GRACE)
+            })
+            .unwrap_or(true);
+        if recent {
+            continue;
+        }
+        if let Err(e) = tokio::fs::remove_file(entry.path()).await {
+            eprintln!("failed to remove attachment blob {name}: {e}");
+        }
+    }
+}
+
+// owner and conversation_id aren't sent to clients, or stored, the message they're on already says
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Attachment {
+    pub id: Uuid,
+    #[serde(skip)]
+    pub owner: Uuid,
+    pub sha256: String, // Also the file name on disk, so identical uploads share a blob
+    pub size: usize,
+    pub mime: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file_name: Option<String>,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub uploaded_at: DateTime<Utc>,
+    // Set once it's been sent with a Say, after that only that conversation can download it
+    #[serde(skip)]
+    pub conversation_id: Option<Uuid>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sniffs_allowed_types_from_their_magic_bytes() {
+        let cases: [(&[u8], &str); 6] = [
+            (b"\x89PNG\r\n\x1a\n rest", "image/png"),
+            (&[0xff, 0xd8, 0xff, 0xe0, 0x00], "image/jpeg"),
+            (b"GIF89a...", "image/gif"),
+            (b"RIFF\x10\x00\x00\x00WEBPVP8 ", "image/webp"),
+            (b"%PDF-1.7\n", "application/pdf"),
+            ("plain text, ünïcode too\n".as_bytes(), "text/plain"),
+        ];
+        for (data, mime) in cases {
+            assert_eq!(sniff_mime_type(data), Some(mime));
+            assert!(ALLOWED_MIME_TYPES.contains(&mime));
+        }
+    }
+
+    #[test]
+    fn binary_that_isnt_an_allowed_type_sniffs_as_nothing() {
+        assert_eq!(sniff_mime_type(b"MZ\x90\x00\x03\x00"), None);
+        assert_eq!(sniff_mime_type(&[0xc3, 0x28]), None);
+        assert_eq!(sniff_mime_type(b"text\0with a nul"), None);
+    }
+}
@@ -1,27 +1,48 @@
-use jsonwebtoken::{TokenData, Validation, decode};
+use jsonwebtoken::{DecodingKey, TokenData, Validation, decode};
 
-use crate::{protocol::Claims, state::RouterState};
+use crate::{directory::display_name, protocol::Claims, state::RouterState};
 
 pub enum AuthenticateError {
     InvalidToken,
+    SessionRevoked,
 }
 
-pub fn handle_authenticate_command(
-    router_state: &mut RouterState,
-    client_id: u64,
-    token: &str,
-) -> Result<String, AuthenticateError> {
+// Also used by the HTTP side, which gets the same tokens as a bearer header
+pub fn decode_claims(decoding_key: &DecodingKey, token: &str) -> Result<Claims, AuthenticateError> {
     let claims: TokenData<Claims> =
-        match decode::<Claims>(token, &router_state.decoding_key, &Validation::default()) {
+        match decode::<Claims>(token, decoding_key, &Validation::default()) {
             Err(e) => {
                 eprintln!("error validating token: {e}");
                 return Err(AuthenticateError::InvalidToken);
             }
             Ok(c) => c,
         };
+    Ok(claims.claims)
+}
+
+pub fn handle_authenticate_command(
+    router_state: &mut RouterState,
+    client_id: u64,
+    token: &str,
+) -> Result<String, AuthenticateError> {
+    let claims = decode_claims(&router_state.decoding_key, token)?;
+
+    if router_state.is_session_revoked(&claims.user.id, claims.sv) {
+        return Err(AuthenticateError::SessionRevoked);
+    }
 
+    // Remember who this is so @username mentions can be resolved without asking auth_service
+    router_state
+        .directory
+        .insert(claims.user.username.to_lowercase(), claims.user.id);
+    // A profile update may have come in after this token was issued, that name is newer
+    if !router_state.names.contains_key(&claims.user.id) {
+        let name = display_name(&claims.user.username, claims.user.display_name.as_deref());
+        router_state.set_name(claims.user.id, name);
+    }
     router_state
-        .connection_to_user
-        .insert(client_id, claims.claims.user.id);
-    Ok(claims.claims.user.id.to_string())
+        .connection_session_version
+        .insert(client_id, claims.sv);
+    router_state.register_session(client_id, claims.user.id, claims.device_id);
+    Ok(claims.user.id.to_string())
 }
@@ -1,16 +1,83 @@
+use std::collections::HashSet;
+
 use uuid::Uuid;
 
 use crate::{conversation::Conversation, state::RouterState};
 
+// Same rules as auth_service registration, anything else can't be a username
+const MAX_USERNAME_CHARS: usize = 24;
+
+pub enum CreateConversationError {
+    Unauthenticated,
+    InvalidParticipant,
+    WithYourself,
+    DeletedUser,
+    UnknownUsername,
+    LookupFailed,
+}
+
+// Ok(None) means the username is being looked up, Event::UsernameResolved finishes the job
 pub fn handle_create_conversation_command(
     router_state: &mut RouterState,
-    participants: Vec<Uuid>,
-) -> Uuid {
+    client_id: u64,
+    participant: &str,
+) -> Result<Option<Uuid>, CreateConversationError> {
+    let Some(current_uuid) = router_state.connection_to_user.get(&client_id).copied() else {
+        return Err(CreateConversationError::Unauthenticated);
+    };
+    if let Ok(participant_uuid) = Uuid::try_parse(participant) {
+        return create_conversation_with(router_state, current_uuid, participant_uuid).map(Some);
+    }
+
+    let username = participant.trim().trim_start_matches('@');
+    if username.is_empty()
+        || username.chars().count() > MAX_USERNAME_CHARS
+        || !username
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        return Err(CreateConversationError::InvalidParticipant);
+    }
+    let username = username.to_lowercase();
+    if let Some(participant_uuid) = router_state.directory.get(&username).copied() {
+        return create_conversation_with(router_state, current_uuid, participant_uuid).map(Some);
+    }
+    // Not knowing the name locally doesn't mean nobody has it, so don't claim it's unknown
+    if router_state.auth_directory.lookup(client_id, username) {
+        Ok(None)
+    } else {
+        Err(CreateConversationError::LookupFailed)
+    }
+}
+
+pub fn create_conversation_with(
+    router_state: &mut RouterState,
+    current_uuid: Uuid,
+    participant_uuid: Uuid,
+) -> Result<Uuid, CreateConversationError> {
+    if current_uuid == participant_uuid {
+        return Err(CreateConversationError::WithYourself);
+    }
+    if router_state.is_deleted_user(&participant_uuid) {
+        return Err(CreateConversationError::DeletedUser);
+    }
+
     // TODO: Conversation De-duping
-    let new_conversation = Conversation::new(participants);
+    let new_conversation = Conversation::new(vec![current_uuid, participant_uuid]);
     let conversation_id = new_conversation.id;
+    router_state.store.save_conversation(&new_conversation);
     router_state
         .conversations
         .insert(conversation_id, new_conversation);
-    conversation_id
+
+    // Both sides are now contacts, so they should see each other's presence straight away
+    for (user_id, other) in [
+        (current_uuid, participant_uuid),
+        (participant_uuid, current_uuid),
+    ] {
+        for connection_id in router_state.connections_for_user(&user_id) {
+            router_state.send_presence_snapshot(connection_id, HashSet::from([other]));
+        }
+    }
+    Ok(conversation_id)
 }
@@ -0,0 +1,32 @@
+use crate::state::RouterState;
+
+pub enum DeleteMessageError {
+    Unauthenticated,
+    MessageDoesntExist,
+    NotSender,
+    AlreadyDeleted,
+}
+
+pub fn handle_delete_message_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    message_id: u64,
+) -> Result<(), DeleteMessageError> {
+    let Some(sender_uuid) = router_state.connection_to_user.get(&connection_id) else {
+        return Err(DeleteMessageError::Unauthenticated);
+    };
+
+    let Some(chat_message) = router_state.find_message(message_id) else {
+        return Err(DeleteMessageError::MessageDoesntExist);
+    };
+
+    if chat_message.from != *sender_uuid {
+        return Err(DeleteMessageError::NotSender);
+    }
+
+    if chat_message.is_deleted() {
+        return Err(DeleteMessageError::AlreadyDeleted);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,46 @@
+use crate::{
+    state::RouterState,
+    text::{TextError, normalize_message},
+};
+
+pub enum EditMessageError {
+    Unauthenticated,
+    MessageDoesntExist,
+    NotSender,
+    AlreadyDeleted,
+    EmptyMessage,
+    MessageTooLong,
+    DisallowedCharacters,
+}
+
+pub fn handle_edit_message_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    message_id: u64,
+    message: &str,
+) -> Result<String, EditMessageError> {
+    let Some(sender_uuid) = router_state.connection_to_user.get(&connection_id) else {
+        return Err(EditMessageError::Unauthenticated);
+    };
+
+    let Some(chat_message) = router_state.find_message(message_id) else {
+        return Err(EditMessageError::MessageDoesntExist);
+    };
+
+    // Same user on any of their devices, not just the connection it was sent from
+    if chat_message.from != *sender_uuid {
+        return Err(EditMessageError::NotSender);
+    }
+
+    if chat_message.is_deleted() {
+        return Err(EditMessageError::AlreadyDeleted);
+    }
+
+    // Same rules as Say, so you can't sneak something in by editing it in afterwards
+    match normalize_message(message, router_state.max_message_graphemes) {
+        Ok(m) => Ok(m),
+        Err(TextError::Empty) => Err(EditMessageError::EmptyMessage),
+        Err(TextError::TooLong) => Err(EditMessageError::MessageTooLong),
+        Err(TextError::DisallowedCharacter) => Err(EditMessageError::DisallowedCharacters),
+    }
+}
@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+use uuid::Uuid;
+
+use crate::state::RouterState;
+
+pub enum EnvelopeError {
+    ConversationDoesntExist,
+    Unauthenticated,
+    NoDevice,
+    InvalidConversation,
+    NoRecipients,
+    NotInvolved,
+}
+
+pub fn handle_envelope_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    conversation_id: &str,
+    ciphertexts: &HashMap<i64, String>,
+) -> Result<Uuid, EnvelopeError> {
+    let Some(sender_uuid) = router_state.connection_to_user.get(&connection_id) else {
+        return Err(EnvelopeError::Unauthenticated);
+    };
+
+    // Recipients need to know which device's session to decrypt with
+    if !router_state
+        .connection_to_device
+        .contains_key(&connection_id)
+    {
+        return Err(EnvelopeError::NoDevice);
+    }
+
+    // We don't inspect the ciphertexts themselves, just that there's somewhere to send them
+    if ciphertexts.is_empty() {
+        return Err(EnvelopeError::NoRecipients);
+    }
+
+    let parsed_conversation_id = match Uuid::try_parse(conversation_id) {
+        Ok(c) => c,
+        Err(_) => {
+            return Err(EnvelopeError::InvalidConversation);
+        }
+    };
+
+    let participants = match router_state.conversations.get(&parsed_conversation_id) {
+        Some(c) => c.participants.clone(),
+        None => {
+            return Err(EnvelopeError::ConversationDoesntExist);
+        }
+    };
+
+    if !participants.contains(sender_uuid) {
+        return Err(EnvelopeError::NotInvolved);
+    }
+
+    Ok(parsed_conversation_id)
+}
@@ -0,0 +1,90 @@
+use uuid::Uuid;
+
+use crate::{message::ReceiptView, protocol::ServerMsg, state::RouterState};
+
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+const MAX_HISTORY_LIMIT: usize = 100;
+
+pub enum HistoryError {
+    ConversationDoesntExist,
+    Unauthenticated,
+    InvalidConversation,
+    NotInvolved,
+    ThreadDoesntExist,
+}
+
+pub fn handle_history_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    conversation_id: &str,
+    before: Option<u64>,
+    limit: Option<usize>,
+    thread: Option<u64>,
+) -> Result<ServerMsg, HistoryError> {
+    let Some(user_uuid) = router_state.connection_to_user.get(&connection_id).copied() else {
+        return Err(HistoryError::Unauthenticated);
+    };
+
+    let parsed_conversation_id = match Uuid::try_parse(conversation_id) {
+        Ok(c) => c,
+        Err(_) => {
+            return Err(HistoryError::InvalidConversation);
+        }
+    };
+
+    let Some(conversation) = router_state.conversations.get(&parsed_conversation_id) else {
+        return Err(HistoryError::ConversationDoesntExist);
+    };
+
+    if !conversation.participants.contains(&user_uuid) {
+        return Err(HistoryError::NotInvolved);
+    }
+
+    if let Some(root_id) = thread
+        && !conversation.messages.iter().any(|m| m.id == root_id)
+    {
+        return Err(HistoryError::ThreadDoesntExist);
+    }
+
+    // Page backwards from `before`, but hand the page back oldest first
+    let limit = limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+    let before = before.unwrap_or(u64::MAX);
+    let mut messages: Vec<_> = conversation
+        .messages
+        .iter()
+        .rev()
+        .filter(|m| m.id < before)
+        .filter(|m| thread.is_none_or(|root_id| m.in_thread(root_id)))
+        .take(limit)
+        .map(|m| m.view(&router_state.names))
+        .collect();
+    messages.reverse();
+    // Senders from before a restart or an import, this page goes out without their names but the next won't
+    let unknown_senders: Vec<Uuid> = messages
+        .iter()
+        .filter(|m| m.from_name.is_none())
+        .filter_map(|m| Uuid::try_parse(&m.from).ok())
+        .collect();
+
+    let receipts = conversation
+        .receipts
+        .iter()
+        .map(|(user_id, receipt)| ReceiptView {
+            user_id: user_id.to_string(),
+            delivered_up_to: receipt.delivered_up_to,
+            read_up_to: receipt.read_up_to,
+        })
+        .collect();
+
+    let history = ServerMsg::History {
+        conversation: parsed_conversation_id.to_string(),
+        thread,
+        messages,
+        receipts,
+        unread: conversation.unread_count(&user_uuid),
+    };
+    router_state.resolve_names(unknown_senders);
+    Ok(history)
+}
@@ -1,3 +1,14 @@
 pub mod authenticate;
 pub mod create_conversation;
+pub mod delete_message;
+pub mod edit_message;
+pub mod envelope;
+pub mod history;
+pub mod react;
+pub mod receipt;
 pub mod say;
+pub mod schedule;
+pub mod search;
+pub mod set_message_ttl;
+pub mod set_status;
+pub mod typing;
@@ -0,0 +1,44 @@
+use crate::{state::RouterState, text::is_valid_emoji};
+
+pub enum ReactError {
+    Unauthenticated,
+    MessageDoesntExist,
+    NotInvolved,
+    MessageDeleted,
+    InvalidEmoji,
+}
+
+// Shared by React and Unreact
+pub fn handle_react_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    message_id: u64,
+    emoji: &str,
+) -> Result<(), ReactError> {
+    let Some(user_uuid) = router_state.connection_to_user.get(&connection_id).copied() else {
+        return Err(ReactError::Unauthenticated);
+    };
+
+    if !is_valid_emoji(emoji) {
+        return Err(ReactError::InvalidEmoji);
+    }
+
+    let Some(chat_message) = router_state.find_message(message_id) else {
+        return Err(ReactError::MessageDoesntExist);
+    };
+    let deleted = chat_message.is_deleted();
+
+    let involved = router_state
+        .conversations
+        .get(&chat_message.conversation_id)
+        .is_some_and(|c| c.participants.contains(&user_uuid));
+    if !involved {
+        return Err(ReactError::NotInvolved);
+    }
+
+    if deleted {
+        return Err(ReactError::MessageDeleted);
+    }
+
+    Ok(())
+}
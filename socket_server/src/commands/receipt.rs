@@ -0,0 +1,46 @@
+use uuid::Uuid;
+
+use crate::state::RouterState;
+
+pub enum ReceiptError {
+    ConversationDoesntExist,
+    Unauthenticated,
+    InvalidConversation,
+    UnknownMessage,
+    NotInvolved,
+}
+
+// Shared by MarkDelivered and MarkRead, returns the conversation and who is acknowledging
+pub fn handle_receipt_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    conversation_id: &str,
+    up_to_message_id: u64,
+) -> Result<(Uuid, Uuid), ReceiptError> {
+    let Some(user_uuid) = router_state.connection_to_user.get(&connection_id).copied() else {
+        return Err(ReceiptError::Unauthenticated);
+    };
+
+    let parsed_conversation_id = match Uuid::try_parse(conversation_id) {
+        Ok(c) => c,
+        Err(_) => {
+            return Err(ReceiptError::InvalidConversation);
+        }
+    };
+
+    let Some(conversation) = router_state.conversations.get(&parsed_conversation_id) else {
+        return Err(ReceiptError::ConversationDoesntExist);
+    };
+
+    if !conversation.participants.contains(&user_uuid) {
+        return Err(ReceiptError::NotInvolved);
+    }
+
+    // You can't acknowledge something that hasn't been sent yet
+    match conversation.last_message_id() {
+        Some(last) if up_to_message_id > 0 && up_to_message_id <= last => {}
+        _ => return Err(ReceiptError::UnknownMessage),
+    }
+
+    Ok((parsed_conversation_id, user_uuid))
+}
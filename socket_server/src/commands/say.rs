@@ -1,13 +1,22 @@
 use uuid::Uuid;
 
-use crate::state::RouterState;
+use crate::{
+    attachment::MAX_ATTACHMENTS_PER_MESSAGE,
+    state::RouterState,
+    text::{TextError, normalize_message},
+};
 
 pub enum SayError {
     ConversationDoesntExist,
     Unauthenticated,
     InvalidConversation,
-    InvalidMessage,
+    EmptyMessage,
+    MessageTooLong,
+    DisallowedCharacters,
     NotInvolved,
+    ReplyDoesntExist,
+    TooManyAttachments,
+    InvalidAttachment,
 }
 
 pub fn handle_say_command(
@@ -15,16 +24,23 @@ pub fn handle_say_command(
     connection_id: u64,
     conversation_id: &str,
     message: &str,
-) -> Result<Uuid, SayError> {
+    reply_to: Option<u64>,
+    attachments: &[Uuid],
+) -> Result<(Uuid, String), SayError> {
     // We need to make sure this user is authenticated
     let Some(sender_uuid) = router_state.connection_to_user.get(&connection_id) else {
         return Err(SayError::Unauthenticated);
     };
 
-    // The chat message is valid
-    if message.is_empty() || message.len() > 256 || !message.is_ascii() {
-        return Err(SayError::InvalidMessage);
-    }
+    // The chat message is valid, and what we'll actually store
+    let message = match normalize_message(message, router_state.max_message_graphemes) {
+        Ok(m) => m,
+        // A file on its own doesn't need a caption
+        Err(TextError::Empty) if !attachments.is_empty() => String::new(),
+        Err(TextError::Empty) => return Err(SayError::EmptyMessage),
+        Err(TextError::TooLong) => return Err(SayError::MessageTooLong),
+        Err(TextError::DisallowedCharacter) => return Err(SayError::DisallowedCharacters),
+    };
 
     // We need the senders UUID
     let parsed_conversation_id = match Uuid::try_parse(conversation_id) {
@@ -46,6 +62,32 @@ pub fn handle_say_command(
         return Err(SayError::NotInvolved);
     }
 
+    // Replies can't reach across conversations, otherwise you could quote something you can't see
+    if let Some(reply_to) = reply_to
+        && router_state.message_to_conversation.get(&reply_to) != Some(&parsed_conversation_id)
+    {
+        return Err(SayError::ReplyDoesntExist);
+    }
+
+    if attachments.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+        return Err(SayError::TooManyAttachments);
+    }
+
+    // You can only send your own uploads, and not lift them into a different conversation
+    for attachment_id in attachments {
+        let valid = router_state
+            .attachments
+            .get(attachment_id)
+            .is_some_and(|a| {
+                a.owner == *sender_uuid
+                    && a.conversation_id
+                        .is_none_or(|c| c == parsed_conversation_id)
+            });
+        if !valid {
+            return Err(SayError::InvalidAttachment);
+        }
+    }
+
     // Continue which continues to the broadcast
-    Ok(parsed_conversation_id)
+    Ok((parsed_conversation_id, message))
 }
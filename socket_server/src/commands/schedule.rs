@@ -0,0 +1,117 @@
+use chrono::{DateTime, Duration, Utc};
+use uuid::Uuid;
+
+use crate::{
+    commands::say::{SayError, handle_say_command},
+    scheduler::{MAX_SCHEDULED_PER_USER, ScheduledMessage, ScheduledView},
+    state::RouterState,
+};
+
+const MAX_SCHEDULE_AHEAD_DAYS: i64 = 365;
+
+pub enum ScheduleError {
+    Say(SayError),
+    Unauthenticated,
+    InvalidConversation,
+    SendAtInPast,
+    SendAtTooFar,
+    TooManyScheduled,
+    InvalidScheduleId,
+    ScheduleDoesntExist,
+}
+
+pub fn handle_schedule_say_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    conversation_id: &str,
+    message: &str,
+    send_at: DateTime<Utc>,
+) -> Result<ScheduledMessage, ScheduleError> {
+    // Everything Say would check now, the text and membership are checked again when it goes out
+    // Rate limits only apply here, as schedule_say, there's no connection to charge at send time
+    let (conversation_id, message) = handle_say_command(
+        router_state,
+        connection_id,
+        conversation_id,
+        message,
+        None,
+        &[],
+    )
+    .map_err(ScheduleError::Say)?;
+    let Some(sender_id) = router_state.connection_to_user.get(&connection_id).copied() else {
+        return Err(ScheduleError::Unauthenticated);
+    };
+
+    let now = Utc::now();
+    if send_at <= now {
+        return Err(ScheduleError::SendAtInPast);
+    }
+    if send_at > now + Duration::days(MAX_SCHEDULE_AHEAD_DAYS) {
+        return Err(ScheduleError::SendAtTooFar);
+    }
+
+    let pending = router_state
+        .scheduled
+        .values()
+        .filter(|s| s.sender_id == sender_id)
+        .count();
+    if pending >= MAX_SCHEDULED_PER_USER {
+        return Err(ScheduleError::TooManyScheduled);
+    }
+
+    Ok(ScheduledMessage {
+        id: Uuid::new_v4(),
+        conversation_id,
+        sender_id,
+        message,
+        send_at,
+    })
+}
+
+// Your own pending messages, optionally just for one conversation, soonest first
+pub fn handle_list_scheduled_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    conversation_id: Option<&str>,
+) -> Result<Vec<ScheduledView>, ScheduleError> {
+    let Some(sender_id) = router_state.connection_to_user.get(&connection_id).copied() else {
+        return Err(ScheduleError::Unauthenticated);
+    };
+
+    let conversation_id = match conversation_id {
+        Some(c) => match Uuid::try_parse(c) {
+            Ok(c) => Some(c),
+            Err(_) => return Err(ScheduleError::InvalidConversation),
+        },
+        None => None,
+    };
+
+    let mut scheduled: Vec<&ScheduledMessage> = router_state
+        .scheduled
+        .values()
+        .filter(|s| s.sender_id == sender_id)
+        .filter(|s| conversation_id.is_none_or(|c| c == s.conversation_id))
+        .collect();
+    scheduled.sort_by_key(|s| s.send_at);
+    Ok(scheduled.into_iter().map(|s| s.view()).collect())
+}
+
+pub fn handle_cancel_scheduled_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    schedule_id: &str,
+) -> Result<Uuid, ScheduleError> {
+    let Some(sender_id) = router_state.connection_to_user.get(&connection_id).copied() else {
+        return Err(ScheduleError::Unauthenticated);
+    };
+
+    let Ok(schedule_id) = Uuid::try_parse(schedule_id) else {
+        return Err(ScheduleError::InvalidScheduleId);
+    };
+
+    // Someone else's schedule looks the same as one that doesn't exist
+    match router_state.scheduled.get(&schedule_id) {
+        Some(s) if s.sender_id == sender_id => Ok(schedule_id),
+        _ => Err(ScheduleError::ScheduleDoesntExist),
+    }
+}
@@ -0,0 +1,70 @@
+use uuid::Uuid;
+
+use crate::{persistence::SearchQuery, state::RouterState};
+
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+const MAX_SEARCH_LIMIT: usize = 50;
+const MAX_QUERY_CHARS: usize = 200;
+
+pub enum SearchError {
+    Unauthenticated,
+    Unavailable,
+    EmptyQuery,
+    QueryTooLong,
+    InvalidConversation,
+    ConversationDoesntExist,
+    NotInvolved,
+}
+
+// Only checks the request, the search itself runs against the database off the router
+pub fn handle_search_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    query: &str,
+    conversation_id: Option<&str>,
+    limit: Option<usize>,
+    cursor: Option<u64>,
+) -> Result<SearchQuery, SearchError> {
+    let Some(user_uuid) = router_state.connection_to_user.get(&connection_id).copied() else {
+        return Err(SearchError::Unauthenticated);
+    };
+
+    // Messages are only indexed when they're persisted
+    if !router_state.store.is_persistent() {
+        return Err(SearchError::Unavailable);
+    }
+
+    let query = query.trim();
+    if query.is_empty() {
+        return Err(SearchError::EmptyQuery);
+    }
+    if query.chars().count() > MAX_QUERY_CHARS {
+        return Err(SearchError::QueryTooLong);
+    }
+
+    let parsed_conversation_id = match conversation_id {
+        Some(conversation_id) => {
+            let Ok(parsed) = Uuid::try_parse(conversation_id) else {
+                return Err(SearchError::InvalidConversation);
+            };
+            let Some(conversation) = router_state.conversations.get(&parsed) else {
+                return Err(SearchError::ConversationDoesntExist);
+            };
+            if !conversation.participants.contains(&user_uuid) {
+                return Err(SearchError::NotInvolved);
+            }
+            Some(parsed)
+        }
+        None => None,
+    };
+
+    Ok(SearchQuery {
+        user_id: user_uuid,
+        query: query.to_string(),
+        conversation_id: parsed_conversation_id,
+        limit: limit
+            .unwrap_or(DEFAULT_SEARCH_LIMIT)
+            .clamp(1, MAX_SEARCH_LIMIT),
+        cursor,
+    })
+}
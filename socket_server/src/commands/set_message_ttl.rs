@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use crate::state::RouterState;
+
+const MIN_MESSAGE_TTL: Duration = Duration::from_secs(5);
+const MAX_MESSAGE_TTL: Duration = Duration::from_secs(28 * 24 * 60 * 60);
+
+pub enum SetMessageTtlError {
+    ConversationDoesntExist,
+    Unauthenticated,
+    InvalidConversation,
+    InvalidTtl,
+    NotInvolved,
+}
+
+// Any participant can change it, the others are told who did
+pub fn handle_set_message_ttl_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    conversation_id: &str,
+    ttl_seconds: Option<u64>,
+) -> Result<(Uuid, Uuid, Option<Duration>), SetMessageTtlError> {
+    let Some(user_uuid) = router_state.connection_to_user.get(&connection_id).copied() else {
+        return Err(SetMessageTtlError::Unauthenticated);
+    };
+
+    let ttl = ttl_seconds.map(Duration::from_secs);
+    if ttl.is_some_and(|t| !(MIN_MESSAGE_TTL..=MAX_MESSAGE_TTL).contains(&t)) {
+        return Err(SetMessageTtlError::InvalidTtl);
+    }
+
+    let parsed_conversation_id = match Uuid::try_parse(conversation_id) {
+        Ok(c) => c,
+        Err(_) => {
+            return Err(SetMessageTtlError::InvalidConversation);
+        }
+    };
+
+    let Some(conversation) = router_state.conversations.get(&parsed_conversation_id) else {
+        return Err(SetMessageTtlError::ConversationDoesntExist);
+    };
+
+    if !conversation.participants.contains(&user_uuid) {
+        return Err(SetMessageTtlError::NotInvolved);
+    }
+
+    Ok((parsed_conversation_id, user_uuid, ttl))
+}
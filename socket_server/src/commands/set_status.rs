@@ -0,0 +1,24 @@
+use crate::{presence::PresenceStatus, state::RouterState};
+
+pub enum SetStatusError {
+    Unauthenticated,
+    InvalidStatus,
+}
+
+pub fn handle_set_status_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    status: PresenceStatus,
+) -> Result<(), SetStatusError> {
+    let Some(user_id) = router_state.connection_to_user.get(&connection_id).copied() else {
+        return Err(SetStatusError::Unauthenticated);
+    };
+
+    // Offline is only ever set by the server when the last connection goes away
+    if status == PresenceStatus::Offline {
+        return Err(SetStatusError::InvalidStatus);
+    }
+
+    router_state.set_presence(user_id, status);
+    Ok(())
+}
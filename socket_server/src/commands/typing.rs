@@ -0,0 +1,38 @@
+use uuid::Uuid;
+
+use crate::state::RouterState;
+
+pub enum TypingError {
+    ConversationDoesntExist,
+    Unauthenticated,
+    InvalidConversation,
+    NotInvolved,
+}
+
+// Shared by TypingStarted and TypingStopped, returns the conversation and who is typing
+pub fn handle_typing_command(
+    router_state: &mut RouterState,
+    connection_id: u64,
+    conversation_id: &str,
+) -> Result<(Uuid, Uuid), TypingError> {
+    let Some(sender_uuid) = router_state.connection_to_user.get(&connection_id).copied() else {
+        return Err(TypingError::Unauthenticated);
+    };
+
+    let parsed_conversation_id = match Uuid::try_parse(conversation_id) {
+        Ok(c) => c,
+        Err(_) => {
+            return Err(TypingError::InvalidConversation);
+        }
+    };
+
+    let Some(conversation) = router_state.conversations.get(&parsed_conversation_id) else {
+        return Err(TypingError::ConversationDoesntExist);
+    };
+
+    if !conversation.participants.contains(&sender_uuid) {
+        return Err(TypingError::NotInvolved);
+    }
+
+    Ok((parsed_conversation_id, sender_uuid))
+}
@@ -1,14 +1,62 @@
+use std::{collections::HashMap, time::Duration};
+
 use uuid::Uuid;
 
+use crate::message::{ChatMessage, Receipt};
+
+// Takes the place of users who deleted their account, in participants and as the sender of their messages
+pub const DELETED_USER_ID: Uuid = Uuid::nil();
+pub const DELETED_USER_NAME: &str = "Deleted user";
+
 #[derive(Clone)]
 pub struct Conversation {
     pub id: Uuid,
     pub participants: Vec<Uuid>,
+    pub messages: Vec<ChatMessage>, // Ordered by id
+    pub receipts: HashMap<Uuid, Receipt>,
+    pub message_ttl: Option<Duration>, // Disappearing messages, None keeps them forever
 }
 
 impl Conversation {
     pub fn new(participants: Vec<Uuid>) -> Conversation {
         let id = Uuid::new_v4();
-        Conversation { id, participants }
+        Conversation {
+            id,
+            participants,
+            messages: vec![],
+            receipts: HashMap::new(),
+            message_ttl: None,
+        }
+    }
+
+    // Two deleted users in one conversation share the one placeholder
+    pub fn replace_participant_with_placeholder(&mut self, user_id: Uuid) -> bool {
+        let Some(index) = self.participants.iter().position(|p| *p == user_id) else {
+            return false;
+        };
+        if self.participants.contains(&DELETED_USER_ID) {
+            self.participants.remove(index);
+        } else {
+            self.participants[index] = DELETED_USER_ID;
+        }
+        self.receipts.remove(&user_id);
+        true
+    }
+
+    pub fn last_message_id(&self) -> Option<u64> {
+        self.messages.last().map(|m| m.id)
+    }
+
+    // Messages from everyone else that this user hasn't read yet
+    pub fn unread_count(&self, user_id: &Uuid) -> usize {
+        let read_up_to = self
+            .receipts
+            .get(user_id)
+            .map(|r| r.read_up_to)
+            .unwrap_or(0);
+        self.messages
+            .iter()
+            .filter(|m| m.id > read_up_to && m.from != *user_id && !m.is_deleted())
+            .count()
     }
 }
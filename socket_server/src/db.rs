@@ -0,0 +1,15 @@
+use std::time::Duration;
+
+use sqlx::{Pool, Postgres, postgres::PgPoolOptions};
+
+pub type DbPool = Pool<Postgres>;
+
+// Fail fast rather than sitting on sqlx's 30 second default when the database isn't up
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub async fn connect(database_url: &str) -> Result<DbPool, sqlx::Error> {
+    PgPoolOptions::new()
+        .acquire_timeout(CONNECT_TIMEOUT)
+        .connect(database_url)
+        .await
+}
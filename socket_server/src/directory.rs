@@ -0,0 +1,122 @@
+use std::{env, time::Duration};
+
+use reqwest::StatusCode;
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedSender;
+use uuid::Uuid;
+
+use crate::protocol::Event;
+
+pub enum UsernameLookup {
+    Found { user_id: Uuid, name: String },
+    NotFound,
+    Failed,
+}
+
+#[derive(Deserialize)]
+struct PublicUser {
+    id: Uuid,
+    username: String,
+    display_name: Option<String>,
+}
+
+// What to call someone in messages
+pub fn display_name(username: &str, display_name: Option<&str>) -> String {
+    display_name
+        .filter(|name| !name.is_empty())
+        .unwrap_or(username)
+        .to_string()
+}
+
+// Asks auth_service for usernames we haven't seen authenticate yet and names of senders we've forgotten,
+// answers come back to the router as events
+// Without AUTH_BASE_URL and INTERNAL_SECRET only the usernames in RouterState::directory can be used
+#[derive(Clone)]
+pub struct AuthDirectory {
+    target: Option<(reqwest::Client, String, String)>,
+    events: UnboundedSender<Event>,
+}
+
+impl AuthDirectory {
+    pub fn from_env(events: UnboundedSender<Event>) -> AuthDirectory {
+        let (Ok(url), Ok(secret)) = (env::var("AUTH_BASE_URL"), env::var("INTERNAL_SECRET")) else {
+            return AuthDirectory {
+                target: None,
+                events,
+            };
+        };
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .build()
+            .expect("failed to build http client");
+        AuthDirectory {
+            target: Some((client, url.trim_end_matches('/').to_string(), secret)),
+            events,
+        }
+    }
+
+    // false if there's no auth_service to ask, otherwise Event::UsernameResolved follows
+    pub fn lookup(&self, client_id: u64, username: String) -> bool {
+        let Some((client, url, secret)) = self.target.clone() else {
+            return false;
+        };
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let path = format!("users/by_username/{username}");
+            let result = match fetch_user(&client, &url, &secret, &path).await {
+                Ok(Some(user)) => UsernameLookup::Found {
+                    user_id: user.id,
+                    name: display_name(&user.username, user.display_name.as_deref()),
+                },
+                Ok(None) => UsernameLookup::NotFound,
+                Err(e) => {
+                    eprintln!("failed to look up {username}: {e}");
+                    UsernameLookup::Failed
+                }
+            };
+            let _ = events.send(Event::UsernameResolved {
+                client_id,
+                username,
+                result,
+            });
+        });
+        true
+    }
+
+    // false if there's no auth_service to ask, otherwise Event::NameResolved follows
+    pub fn resolve_name(&self, user_id: Uuid) -> bool {
+        let Some((client, url, secret)) = self.target.clone() else {
+            return false;
+        };
+        let events = self.events.clone();
+        tokio::spawn(async move {
+            let path = format!("users/{user_id}");
+            let name = match fetch_user(&client, &url, &secret, &path).await {
+                Ok(user) => user.map(|u| display_name(&u.username, u.display_name.as_deref())),
+                Err(e) => {
+                    eprintln!("failed to look up the name of {user_id}: {e}");
+                    None
+                }
+            };
+            let _ = events.send(Event::NameResolved { user_id, name });
+        });
+        true
+    }
+}
+
+async fn fetch_user(
+    client: &reqwest::Client,
+    url: &str,
+    secret: &str,
+    path: &str,
+) -> Result<Option<PublicUser>, reqwest::Error> {
+    let res = client
+        .get(format!("{url}/{path}"))
+        .bearer_auth(secret)
+        .send()
+        .await?;
+    if res.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    Ok(Some(res.error_for_status()?.json().await?))
+}
@@ -0,0 +1,338 @@
+use std::{collections::HashMap, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::{
+    conversation::Conversation,
+    db::DbPool,
+    message::{ChatMessage, Receipt},
+    persistence::{Write, apply_write},
+};
+
+// One of these per line of a JSON Lines export, the conversation always comes first
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ExportLine {
+    Conversation {
+        id: Uuid,
+        participants: Vec<Uuid>,
+        message_ttl_seconds: Option<u64>,
+        exported_at: DateTime<Utc>,
+    },
+    Message(ChatMessage),
+    Receipt {
+        user_id: Uuid,
+        delivered_up_to: u64,
+        read_up_to: u64,
+    },
+}
+
+pub enum ExportFormat {
+    JsonLines,
+    Text,
+}
+
+impl ExportFormat {
+    pub fn parse(format: Option<&str>) -> Option<ExportFormat> {
+        match format.unwrap_or("jsonl") {
+            "jsonl" => Some(ExportFormat::JsonLines),
+            "text" => Some(ExportFormat::Text),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::JsonLines => "application/x-ndjson",
+            ExportFormat::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::JsonLines => "jsonl",
+            ExportFormat::Text => "txt",
+        }
+    }
+}
+
+// Every line ends in a newline, and each one is only serialized when the body stream gets to it
+pub type ExportLines = Box<dyn Iterator<Item = String> + Send>;
+
+// names is only for the text export, the JSON one keeps ids so it can be imported again
+pub fn export_lines(
+    conversation: Conversation,
+    names: HashMap<Uuid, String>,
+    format: ExportFormat,
+) -> ExportLines {
+    match format {
+        ExportFormat::JsonLines => json_lines(conversation),
+        ExportFormat::Text => text_lines(conversation, names),
+    }
+}
+
+fn json_lines(conversation: Conversation) -> ExportLines {
+    let header = ExportLine::Conversation {
+        id: conversation.id,
+        participants: conversation.participants,
+        message_ttl_seconds: conversation.message_ttl.map(|t| t.as_secs()),
+        exported_at: Utc::now(),
+    };
+    let messages = conversation.messages.into_iter().map(ExportLine::Message);
+    let receipts =
+        conversation
+            .receipts
+            .into_iter()
+            .map(|(user_id, receipt)| ExportLine::Receipt {
+                user_id,
+                delivered_up_to: receipt.delivered_up_to,
+                read_up_to: receipt.read_up_to,
+            });
+
+    Box::new(
+        std::iter::once(header)
+            .chain(messages)
+            .chain(receipts)
+            .filter_map(|line| match serde_json::to_string(&line) {
+                Ok(json) => Some(json + "\n"),
+                Err(e) => {
+                    eprintln!("failed to serialize export line: {e}");
+                    None
+                }
+            }),
+    )
+}
+
+fn timestamp(at: DateTime<Utc>) -> String {
+    at.format("%Y-%m-%d %H:%M:%S UTC").to_string()
+}
+
+// Falls back to the id for anyone we never learned a name for
+fn display_name(names: &HashMap<Uuid, String>, user_id: &Uuid) -> String {
+    names
+        .get(user_id)
+        .cloned()
+        .unwrap_or_else(|| user_id.to_string())
+}
+
+fn text_lines(conversation: Conversation, names: HashMap<Uuid, String>) -> ExportLines {
+    let header = vec![
+        format!("Conversation {}\n", conversation.id),
+        format!(
+            "Participants: {}\n",
+            conversation
+                .participants
+                .iter()
+                // Names aren't unique, the ids here say who's who
+                .map(|p| match names.get(p) {
+                    Some(name) => format!("{name} ({p})"),
+                    None => p.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        format!("Exported at {}\n", timestamp(Utc::now())),
+        "\n".to_string(),
+    ];
+    Box::new(
+        header.into_iter().chain(
+            conversation
+                .messages
+                .into_iter()
+                .flat_map(move |message| message_text(message, &names)),
+        ),
+    )
+}
+
+// One message, plus its edits, attachments and reactions on indented lines
+fn message_text(message: ChatMessage, names: &HashMap<Uuid, String>) -> Vec<String> {
+    let mut header = format!(
+        "[{}] #{} {}",
+        timestamp(message.sent_at),
+        message.id,
+        display_name(names, &message.from)
+    );
+    if let Some(reply_to) = message.reply_to {
+        header.push_str(&format!(" (reply to #{reply_to})"));
+    }
+
+    if let Some(deleted_at) = message.deleted_at {
+        return vec![format!(
+            "{header}: [deleted at {}]\n",
+            timestamp(deleted_at)
+        )];
+    }
+
+    if let Some(edited_at) = message.edited_at {
+        header.push_str(&format!(" (edited {})", timestamp(edited_at)));
+    }
+    // Keep continuation lines indented so the transcript stays readable
+    let mut lines = vec![format!(
+        "{header}: {}\n",
+        message.message.replace('\n', "\n    ")
+    )];
+    for edit in &message.edits {
+        lines.push(format!(
+            "    previously [{}]: {}\n",
+            timestamp(edit.written_at),
+            edit.message.replace('\n', "\n        ")
+        ));
+    }
+    for attachment in &message.attachments {
+        lines.push(format!(
+            "    attachment: {} ({}, {} bytes, sha256 {})\n",
+            attachment.file_name.as_deref().unwrap_or("unnamed"),
+            attachment.mime,
+            attachment.size,
+            attachment.sha256
+        ));
+    }
+    for (emoji, users) in &message.reactions {
+        lines.push(format!("    reacted {emoji}: {}\n", users.len()));
+    }
+    lines
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io(std::io::Error),
+    Parse {
+        line: usize,
+        error: serde_json::Error,
+    },
+    MissingConversation,
+    WrongConversation {
+        line: usize,
+    },
+    AlreadyExists,
+    Database(sqlx::Error),
+}
+
+impl std::fmt::Display for ImportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImportError::Io(e) => write!(f, "couldn't read export: {e}"),
+            ImportError::Parse { line, error } => write!(f, "line {line} is invalid: {error}"),
+            ImportError::MissingConversation => {
+                write!(f, "export doesn't start with a conversation line")
+            }
+            ImportError::WrongConversation { line } => {
+                write!(f, "line {line} belongs to a different conversation")
+            }
+            ImportError::AlreadyExists => write!(
+                f,
+                "the conversation or some of its messages already exist in this database"
+            ),
+            ImportError::Database(e) => write!(f, "database error: {e}"),
+        }
+    }
+}
+
+// Restores a JSON Lines export, the server should be stopped since it only reads the database on startup
+pub async fn import_file(db: &DbPool, path: &str) -> Result<(Uuid, usize), ImportError> {
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .map_err(ImportError::Io)?;
+
+    let mut conversation: Option<Conversation> = None;
+    let mut messages: Vec<ChatMessage> = vec![];
+    let mut receipts: HashMap<Uuid, Receipt> = HashMap::new();
+    for (i, raw) in contents.lines().enumerate() {
+        if raw.trim().is_empty() {
+            continue;
+        }
+        let line = i + 1;
+        let parsed: ExportLine =
+            serde_json::from_str(raw).map_err(|error| ImportError::Parse { line, error })?;
+        match (parsed, &conversation) {
+            (
+                ExportLine::Conversation {
+                    id,
+                    participants,
+                    message_ttl_seconds,
+                    ..
+                },
+                None,
+            ) => {
+                let mut c = Conversation::new(participants);
+                c.id = id;
+                c.message_ttl = message_ttl_seconds.map(Duration::from_secs);
+                conversation = Some(c);
+            }
+            (_, None) => return Err(ImportError::MissingConversation),
+            (ExportLine::Conversation { .. }, Some(_)) => {
+                return Err(ImportError::WrongConversation { line });
+            }
+            (ExportLine::Message(message), Some(c)) => {
+                if message.conversation_id != c.id {
+                    return Err(ImportError::WrongConversation { line });
+                }
+                messages.push(message);
+            }
+            (
+                ExportLine::Receipt {
+                    user_id,
+                    delivered_up_to,
+                    read_up_to,
+                },
+                Some(_),
+            ) => {
+                receipts.insert(
+                    user_id,
+                    Receipt {
+                        delivered_up_to,
+                        read_up_to,
+                    },
+                );
+            }
+        }
+    }
+    let Some(conversation) = conversation else {
+        return Err(ImportError::MissingConversation);
+    };
+
+    // All or nothing, so a failed import can simply be run again
+    let mut transaction = db.begin().await.map_err(ImportError::Database)?;
+
+    // Message ids are global, so anything already there would get overwritten
+    let ids: Vec<i64> = messages.iter().map(|m| m.id as i64).collect();
+    let (existing,): (i64,) = sqlx::query_as(
+        "SELECT (SELECT COUNT(*) FROM conversations WHERE id = $1)
+              + (SELECT COUNT(*) FROM messages WHERE id = ANY($2))",
+    )
+    .bind(conversation.id)
+    .bind(&ids)
+    .fetch_one(&mut *transaction)
+    .await
+    .map_err(ImportError::Database)?;
+    if existing > 0 {
+        return Err(ImportError::AlreadyExists);
+    }
+
+    let conversation_id = conversation.id;
+    let count = messages.len();
+    let mut writes = vec![Write::SaveConversation {
+        id: conversation.id,
+        participants: conversation.participants,
+        message_ttl: conversation.message_ttl,
+    }];
+    writes.extend(messages.into_iter().map(Write::SaveMessage));
+    writes.extend(
+        receipts
+            .into_iter()
+            .map(|(user_id, receipt)| Write::SaveReceipt {
+                conversation_id,
+                user_id,
+                receipt,
+            }),
+    );
+    for write in writes {
+        apply_write(&mut transaction, write)
+            .await
+            .map_err(ImportError::Database)?;
+    }
+    transaction.commit().await.map_err(ImportError::Database)?;
+    Ok((conversation_id, count))
+}
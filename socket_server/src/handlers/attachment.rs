@@ -0,0 +1,57 @@
+use std::collections::HashSet;
+
+use chrono::Utc;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::{
+    attachment::{Attachment, PENDING_ATTACHMENT_TTL},
+    state::RouterState,
+};
+
+pub fn handle_attachment_uploaded_event(router_state: &mut RouterState, attachment: Attachment) {
+    router_state.attachments.insert(attachment.id, attachment);
+}
+
+pub fn handle_authorize_attachment_event(
+    router_state: &mut RouterState,
+    user_id: Uuid,
+    attachment_id: Uuid,
+    reply: oneshot::Sender<Option<Attachment>>,
+) {
+    let allowed = router_state
+        .attachments
+        .get(&attachment_id)
+        .filter(|a| {
+            a.owner == user_id
+                || a.conversation_id.is_some_and(|conversation_id| {
+                    router_state
+                        .conversations
+                        .get(&conversation_id)
+                        .is_some_and(|c| c.participants.contains(&user_id))
+                })
+        })
+        .cloned();
+
+    // The HTTP side may have given up waiting, nothing to do about it
+    let _ = reply.send(allowed);
+}
+
+pub fn handle_referenced_blobs_event(
+    router_state: &mut RouterState,
+    reply: oneshot::Sender<Option<HashSet<String>>>,
+) {
+    let cutoff = Utc::now() - PENDING_ATTACHMENT_TTL;
+    router_state
+        .attachments
+        .retain(|_, a| a.conversation_id.is_some() || a.uploaded_at > cutoff);
+
+    let referenced = router_state.blobs_loaded.then(|| {
+        router_state
+            .attachments
+            .values()
+            .map(|a| a.sha256.clone())
+            .collect()
+    });
+    let _ = reply.send(referenced);
+}
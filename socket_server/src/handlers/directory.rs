@@ -0,0 +1,69 @@
+use uuid::Uuid;
+
+use crate::{
+    commands::create_conversation::{CreateConversationError, create_conversation_with},
+    directory::UsernameLookup,
+    handlers::received::create_conversation_error_message,
+    protocol::ServerMsg,
+    state::RouterState,
+};
+
+// Finishes a CreateConversation that named someone we hadn't seen before
+pub fn handle_username_resolved_event(
+    router_state: &mut RouterState,
+    client_id: u64,
+    username: String,
+    result: UsernameLookup,
+) {
+    // They may have gone away while auth_service was answering
+    let Some(tx) = router_state.connections.get(&client_id).cloned() else {
+        return;
+    };
+    let Some(current_uuid) = router_state.connection_to_user.get(&client_id).copied() else {
+        return;
+    };
+
+    let created = match result {
+        UsernameLookup::Found {
+            user_id: participant_uuid,
+            name,
+        } => {
+            router_state.directory.insert(username, participant_uuid);
+            router_state.set_name(participant_uuid, name);
+            create_conversation_with(router_state, current_uuid, participant_uuid)
+        }
+        UsernameLookup::NotFound => Err(CreateConversationError::UnknownUsername),
+        UsernameLookup::Failed => Err(CreateConversationError::LookupFailed),
+    };
+    let msg = match created {
+        Ok(conversation_id) => ServerMsg::Info {
+            message: format!("Created conversation: {conversation_id}"),
+        },
+        Err(e) => ServerMsg::Error {
+            message: create_conversation_error_message(e).to_string(),
+        },
+    };
+    router_state.send_or_disconnect_server_msg(client_id, &tx, &msg);
+}
+
+pub fn handle_profile_updated_event(router_state: &mut RouterState, user_id: Uuid, name: String) {
+    // Deleted users don't get to come back by renaming themselves
+    if !router_state.is_deleted_user(&user_id) {
+        router_state.set_name(user_id, name);
+    }
+}
+
+pub fn handle_name_resolved_event(
+    router_state: &mut RouterState,
+    user_id: Uuid,
+    name: Option<String>,
+) {
+    router_state.pending_names.remove(&user_id);
+    // A profile update that beat the lookup here is at least as new
+    if let Some(name) = name
+        && !router_state.is_deleted_user(&user_id)
+        && !router_state.names.contains_key(&user_id)
+    {
+        router_state.set_name(user_id, name);
+    }
+}
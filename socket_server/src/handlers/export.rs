@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::{conversation::Conversation, state::RouterState};
+
+// Hands over a copy so the HTTP side can take its time writing it out
+pub fn handle_export_conversation_event(
+    router_state: &RouterState,
+    user_id: Uuid,
+    conversation_id: Uuid,
+    reply: oneshot::Sender<Option<(Conversation, HashMap<Uuid, String>)>>,
+) {
+    let conversation = router_state
+        .conversations
+        .get(&conversation_id)
+        .filter(|c| c.participants.contains(&user_id))
+        .cloned()
+        .map(|conversation| {
+            // Only who's in it or wrote in it, not everyone we've ever heard of
+            let names = conversation
+                .participants
+                .iter()
+                .chain(conversation.messages.iter().map(|m| &m.from))
+                .filter_map(|id| Some((*id, router_state.names.get(id)?.clone())))
+                .collect();
+            (conversation, names)
+        });
+
+    // The HTTP side may have given up waiting, nothing to do about it
+    let _ = reply.send(conversation);
+}
@@ -1,3 +1,10 @@
+pub mod attachment;
 pub mod connected;
+pub mod directory;
 pub mod disconnected;
+pub mod export;
 pub mod received;
+pub mod scheduled;
+pub mod sessions;
+pub mod sweep;
+pub mod tick;
@@ -1,8 +1,27 @@
+use tokio::{sync::mpsc::UnboundedSender, time::Instant};
+use tokio_tungstenite::tungstenite::{Message, protocol::frame::coding::CloseCode};
 use uuid::Uuid;
 
 use crate::commands::authenticate::handle_authenticate_command;
-use crate::commands::create_conversation::handle_create_conversation_command;
+use crate::commands::create_conversation::{
+    CreateConversationError, handle_create_conversation_command,
+};
+use crate::commands::delete_message::{DeleteMessageError, handle_delete_message_command};
+use crate::commands::edit_message::{EditMessageError, handle_edit_message_command};
+use crate::commands::envelope::{EnvelopeError, handle_envelope_command};
+use crate::commands::history::{HistoryError, handle_history_command};
+use crate::commands::react::{ReactError, handle_react_command};
+use crate::commands::receipt::{ReceiptError, handle_receipt_command};
 use crate::commands::say::{SayError, handle_say_command};
+use crate::commands::schedule::{
+    ScheduleError, handle_cancel_scheduled_command, handle_list_scheduled_command,
+    handle_schedule_say_command,
+};
+use crate::commands::search::{SearchError, handle_search_command};
+use crate::commands::set_message_ttl::{SetMessageTtlError, handle_set_message_ttl_command};
+use crate::commands::set_status::{SetStatusError, handle_set_status_command};
+use crate::commands::typing::{TypingError, handle_typing_command};
+use crate::message::ReceiptStatus;
 use crate::protocol::ServerMsg;
 use crate::{protocol::Command, state::RouterState};
 
@@ -10,6 +29,9 @@ pub fn handle_received_event(router_state: &mut RouterState, client_id: u64, com
     let Some(tx) = router_state.connections.get(&client_id).cloned() else {
         return;
     };
+    if rate_limited(router_state, client_id, &tx, command.kind()) {
+        return;
+    }
     match command {
         Command::Authenticate { token } => {
             // If the user is already authenticated, ignore this command
@@ -44,71 +66,192 @@ pub fn handle_received_event(router_state: &mut RouterState, client_id: u64, com
                     message: format!("AUTH OK {user_id}"),
                 },
             );
+
+            if let Some(current_uuid) = router_state.connection_to_user.get(&client_id).copied() {
+                let contacts = router_state.contacts_of(&current_uuid);
+                router_state.send_presence_snapshot(client_id, contacts);
+            }
         }
         Command::CreateConversation { participant } => {
-            let parsed_participant_uuid = match Uuid::try_parse(&participant) {
-                Ok(p) => p,
-                Err(_) => {
+            match handle_create_conversation_command(router_state, client_id, &participant) {
+                Err(e) => {
                     router_state.send_or_disconnect_server_msg(
                         client_id,
                         &tx,
                         &ServerMsg::Error {
-                            message: "Failed to parse participant ID".to_string(),
+                            message: create_conversation_error_message(e).to_string(),
+                        },
+                    );
+                }
+                Ok(Some(conversation_id)) => {
+                    router_state.send_or_disconnect_server_msg(
+                        client_id,
+                        &tx,
+                        &ServerMsg::Info {
+                            message: format!("Created conversation: {conversation_id}"),
+                        },
+                    );
+                }
+                // Waiting on auth_service, the answer comes back as Event::UsernameResolved
+                Ok(None) => {}
+            }
+        }
+        Command::Say {
+            message,
+            conversation_id,
+            reply_to,
+            attachments,
+        } => {
+            let (conversation_id, message) = match handle_say_command(
+                router_state,
+                client_id,
+                &conversation_id,
+                &message,
+                reply_to,
+                &attachments,
+            ) {
+                Err(e) => {
+                    let error_msg = say_error_message(e);
+                    router_state.send_or_disconnect_server_msg(
+                        client_id,
+                        &tx,
+                        &ServerMsg::Error {
+                            message: error_msg.to_string(),
                         },
                     );
                     return;
                 }
+                Ok(c) => c,
             };
 
-            // Get current UUID
-            let current_uuid = match router_state.connection_to_user.get(&client_id) {
-                Some(i) => i,
-                None => {
+            let Some(sender_uuid) = router_state.connection_to_user.get(&client_id).copied() else {
+                return;
+            };
+
+            // Sending the message is the end of typing it
+            router_state.stop_typing(conversation_id, sender_uuid);
+            router_state.post_message(conversation_id, sender_uuid, message, reply_to, attachments);
+        }
+        Command::SendEnvelope {
+            conversation_id,
+            ciphertexts,
+        } => {
+            let conversation_id: Uuid = match handle_envelope_command(
+                router_state,
+                client_id,
+                &conversation_id,
+                &ciphertexts,
+            ) {
+                Err(e) => {
+                    let error_msg = match e {
+                        EnvelopeError::Unauthenticated => "You must authenticate first",
+                        EnvelopeError::NoDevice => {
+                            "You must log in with a device to send envelopes"
+                        }
+                        EnvelopeError::NoRecipients => "Envelope has no recipients",
+                        EnvelopeError::ConversationDoesntExist => "Conversation doesn't exist",
+                        EnvelopeError::InvalidConversation => "Conversation ID is invalid",
+                        EnvelopeError::NotInvolved => "You are not in this conversation",
+                    };
                     router_state.send_or_disconnect_server_msg(
                         client_id,
                         &tx,
                         &ServerMsg::Error {
-                            message: "You must authenticate first".to_string(),
+                            message: error_msg.to_string(),
                         },
                     );
                     return;
                 }
+                Ok(c) => c,
             };
 
-            if *current_uuid == parsed_participant_uuid {
+            router_state.send_envelopes_to_devices(conversation_id, client_id, ciphertexts);
+        }
+        Command::SetStatus { status } => {
+            if let Err(e) = handle_set_status_command(router_state, client_id, status) {
+                let error_msg = match e {
+                    SetStatusError::Unauthenticated => "You must authenticate first",
+                    SetStatusError::InvalidStatus => {
+                        "Status must be online, away or do_not_disturb"
+                    }
+                };
                 router_state.send_or_disconnect_server_msg(
                     client_id,
                     &tx,
                     &ServerMsg::Error {
-                        message: "You cannot create a conversation with yourself".to_string(),
+                        message: error_msg.to_string(),
                     },
                 );
-                return;
             }
-            let participants: Vec<Uuid> = vec![*current_uuid, parsed_participant_uuid];
-            let new_conversation_id =
-                handle_create_conversation_command(router_state, participants);
-            router_state.send_or_disconnect_server_msg(
-                client_id,
-                &tx,
-                &ServerMsg::Info {
-                    message: format!("Created conversation: {new_conversation_id}"),
-                },
-            );
         }
-        Command::Say {
-            message,
+        Command::TypingStarted { conversation_id } => {
+            match handle_typing_command(router_state, client_id, &conversation_id) {
+                Ok((conversation_id, user_id)) => {
+                    router_state.start_typing(conversation_id, user_id)
+                }
+                Err(e) => send_typing_error(router_state, client_id, &tx, e),
+            }
+        }
+        Command::TypingStopped { conversation_id } => {
+            match handle_typing_command(router_state, client_id, &conversation_id) {
+                Ok((conversation_id, user_id)) => {
+                    router_state.stop_typing(conversation_id, user_id)
+                }
+                Err(e) => send_typing_error(router_state, client_id, &tx, e),
+            }
+        }
+        Command::MarkDelivered {
+            conversation_id,
+            up_to_message_id,
+        } => match handle_receipt_command(
+            router_state,
+            client_id,
+            &conversation_id,
+            up_to_message_id,
+        ) {
+            Ok((conversation_id, user_id)) => router_state.update_receipt(
+                conversation_id,
+                user_id,
+                ReceiptStatus::Delivered,
+                up_to_message_id,
+            ),
+            Err(e) => send_receipt_error(router_state, client_id, &tx, e),
+        },
+        Command::MarkRead {
             conversation_id,
+            up_to_message_id,
+        } => match handle_receipt_command(
+            router_state,
+            client_id,
+            &conversation_id,
+            up_to_message_id,
+        ) {
+            Ok((conversation_id, user_id)) => router_state.update_receipt(
+                conversation_id,
+                user_id,
+                ReceiptStatus::Read,
+                up_to_message_id,
+            ),
+            Err(e) => send_receipt_error(router_state, client_id, &tx, e),
+        },
+        Command::EditMessage {
+            message_id,
+            message,
         } => {
-            let conversation_id: Uuid =
-                match handle_say_command(router_state, client_id, &conversation_id, &message) {
+            let message =
+                match handle_edit_message_command(router_state, client_id, message_id, &message) {
+                    Ok(m) => m,
                     Err(e) => {
                         let error_msg = match e {
-                            SayError::InvalidMessage => "Invalid message",
-                            SayError::Unauthenticated => "You must authenticate first",
-                            SayError::ConversationDoesntExist => "Conversation doesn't exist",
-                            SayError::InvalidConversation => "Conversation ID is invalid",
-                            SayError::NotInvolved => "You are not in this conversation",
+                            EditMessageError::Unauthenticated => "You must authenticate first",
+                            EditMessageError::MessageDoesntExist => "Message doesn't exist",
+                            EditMessageError::NotSender => "You can only edit your own messages",
+                            EditMessageError::AlreadyDeleted => "Message has been deleted",
+                            EditMessageError::EmptyMessage => "Message is empty",
+                            EditMessageError::MessageTooLong => "Message is too long",
+                            EditMessageError::DisallowedCharacters => {
+                                "Message contains disallowed characters"
+                            }
                         };
                         router_state.send_or_disconnect_server_msg(
                             client_id,
@@ -119,11 +262,330 @@ pub fn handle_received_event(router_state: &mut RouterState, client_id: u64, com
                         );
                         return;
                     }
-                    Ok(c) => c,
                 };
+            router_state.edit_message(message_id, message);
+        }
+        Command::DeleteMessage { message_id } => {
+            if let Err(e) = handle_delete_message_command(router_state, client_id, message_id) {
+                let error_msg = match e {
+                    DeleteMessageError::Unauthenticated => "You must authenticate first",
+                    DeleteMessageError::MessageDoesntExist => "Message doesn't exist",
+                    DeleteMessageError::NotSender => "You can only delete your own messages",
+                    DeleteMessageError::AlreadyDeleted => "Message has already been deleted",
+                };
+                router_state.send_or_disconnect_server_msg(
+                    client_id,
+                    &tx,
+                    &ServerMsg::Error {
+                        message: error_msg.to_string(),
+                    },
+                );
+                return;
+            }
+            router_state.delete_message(message_id);
+        }
+        Command::React { message_id, emoji } => {
+            react(router_state, client_id, &tx, message_id, emoji, true)
+        }
+        Command::Unreact { message_id, emoji } => {
+            react(router_state, client_id, &tx, message_id, emoji, false)
+        }
+        Command::SetMessageTtl {
+            conversation_id,
+            ttl_seconds,
+        } => {
+            match handle_set_message_ttl_command(
+                router_state,
+                client_id,
+                &conversation_id,
+                ttl_seconds,
+            ) {
+                Ok((conversation_id, user_id, ttl)) => {
+                    router_state.set_message_ttl(conversation_id, user_id, ttl)
+                }
+                Err(e) => {
+                    let error_msg = match e {
+                        SetMessageTtlError::Unauthenticated => "You must authenticate first",
+                        SetMessageTtlError::ConversationDoesntExist => "Conversation doesn't exist",
+                        SetMessageTtlError::InvalidConversation => "Conversation ID is invalid",
+                        SetMessageTtlError::InvalidTtl => {
+                            "Message lifetime must be between 5 seconds and 4 weeks"
+                        }
+                        SetMessageTtlError::NotInvolved => "You are not in this conversation",
+                    };
+                    router_state.send_or_disconnect_server_msg(
+                        client_id,
+                        &tx,
+                        &ServerMsg::Error {
+                            message: error_msg.to_string(),
+                        },
+                    );
+                }
+            }
+        }
+        Command::ScheduleSay {
+            conversation_id,
+            message,
+            send_at,
+        } => {
+            let msg = match handle_schedule_say_command(
+                router_state,
+                client_id,
+                &conversation_id,
+                &message,
+                send_at,
+            ) {
+                Ok(scheduled) => {
+                    let view = scheduled.view();
+                    router_state.schedule_message(scheduled);
+                    ServerMsg::Scheduled(view)
+                }
+                Err(e) => schedule_error_msg(e),
+            };
+            router_state.send_or_disconnect_server_msg(client_id, &tx, &msg);
+        }
+        Command::ListScheduled { conversation_id } => {
+            let msg = match handle_list_scheduled_command(
+                router_state,
+                client_id,
+                conversation_id.as_deref(),
+            ) {
+                Ok(scheduled) => ServerMsg::ScheduledList { scheduled },
+                Err(e) => schedule_error_msg(e),
+            };
+            router_state.send_or_disconnect_server_msg(client_id, &tx, &msg);
+        }
+        Command::CancelScheduled { schedule_id } => {
+            let msg = match handle_cancel_scheduled_command(router_state, client_id, &schedule_id) {
+                Ok(schedule_id) => {
+                    router_state.cancel_scheduled(schedule_id);
+                    ServerMsg::ScheduledCancelled {
+                        id: schedule_id.to_string(),
+                    }
+                }
+                Err(e) => schedule_error_msg(e),
+            };
+            router_state.send_or_disconnect_server_msg(client_id, &tx, &msg);
+        }
+        Command::GetHistory {
+            conversation_id,
+            before,
+            limit,
+            thread,
+        } => {
+            let msg = match handle_history_command(
+                router_state,
+                client_id,
+                &conversation_id,
+                before,
+                limit,
+                thread,
+            ) {
+                Ok(history) => history,
+                Err(e) => {
+                    let error_msg = match e {
+                        HistoryError::Unauthenticated => "You must authenticate first",
+                        HistoryError::ConversationDoesntExist => "Conversation doesn't exist",
+                        HistoryError::InvalidConversation => "Conversation ID is invalid",
+                        HistoryError::NotInvolved => "You are not in this conversation",
+                        HistoryError::ThreadDoesntExist => {
+                            "Thread doesn't exist in this conversation"
+                        }
+                    };
+                    ServerMsg::Error {
+                        message: error_msg.to_string(),
+                    }
+                }
+            };
+            router_state.send_or_disconnect_server_msg(client_id, &tx, &msg);
+        }
+        Command::SearchMessages {
+            query,
+            conversation_id,
+            limit,
+            cursor,
+        } => {
+            match handle_search_command(
+                router_state,
+                client_id,
+                &query,
+                conversation_id.as_deref(),
+                limit,
+                cursor,
+            ) {
+                Ok(search) => router_state.store.search(search, tx),
+                Err(e) => {
+                    let error_msg = match e {
+                        SearchError::Unauthenticated => "You must authenticate first",
+                        SearchError::Unavailable => "Search isn't available on this server",
+                        SearchError::EmptyQuery => "Search query can't be empty",
+                        SearchError::QueryTooLong => "Search query is too long",
+                        SearchError::InvalidConversation => "Conversation ID is invalid",
+                        SearchError::ConversationDoesntExist => "Conversation doesn't exist",
+                        SearchError::NotInvolved => "You are not in this conversation",
+                    };
+                    router_state.send_or_disconnect_server_msg(
+                        client_id,
+                        &tx,
+                        &ServerMsg::Error {
+                            message: error_msg.to_string(),
+                        },
+                    );
+                }
+            }
+        }
+    }
+}
+
+// Checked before anything else so a flood never gets as far as the handlers
+fn rate_limited(
+    router_state: &mut RouterState,
+    client_id: u64,
+    tx: &UnboundedSender<Message>,
+    kind: &'static str,
+) -> bool {
+    let now = Instant::now();
+    let user_id = router_state.connection_to_user.get(&client_id).copied();
+    let Err(retry_after) = router_state
+        .rate_limiter
+        .check(client_id, user_id, kind, now)
+    else {
+        return false;
+    };
+
+    if router_state.rate_limiter.strike(client_id, now) {
+        router_state.close_connection(client_id, CloseCode::Policy, "Too many requests");
+        return true;
+    }
+
+    router_state.send_or_disconnect_server_msg(
+        client_id,
+        tx,
+        &ServerMsg::RateLimited {
+            command: kind.to_string(),
+            retry_after_ms: retry_after.as_millis() as u64,
+        },
+    );
+    true
+}
 
-            // We'll use one of the optimized calls since we're broadcasting
-            router_state.send_server_msg_to_conversation(conversation_id, client_id, &message);
+fn send_typing_error(
+    router_state: &mut RouterState,
+    client_id: u64,
+    tx: &UnboundedSender<Message>,
+    e: TypingError,
+) {
+    let error_msg = match e {
+        TypingError::Unauthenticated => "You must authenticate first",
+        TypingError::ConversationDoesntExist => "Conversation doesn't exist",
+        TypingError::InvalidConversation => "Conversation ID is invalid",
+        TypingError::NotInvolved => "You are not in this conversation",
+    };
+    router_state.send_or_disconnect_server_msg(
+        client_id,
+        tx,
+        &ServerMsg::Error {
+            message: error_msg.to_string(),
+        },
+    );
+}
+
+pub fn create_conversation_error_message(e: CreateConversationError) -> &'static str {
+    match e {
+        CreateConversationError::Unauthenticated => "You must authenticate first",
+        CreateConversationError::InvalidParticipant => {
+            "Participant must be a user ID or a username"
+        }
+        CreateConversationError::WithYourself => "You cannot create a conversation with yourself",
+        CreateConversationError::DeletedUser => "That user has deleted their account",
+        CreateConversationError::UnknownUsername => "No user with that username",
+        CreateConversationError::LookupFailed => {
+            "Username lookup unavailable, try again or use their user ID"
         }
     }
 }
+
+fn say_error_message(e: SayError) -> &'static str {
+    match e {
+        SayError::EmptyMessage => "Message is empty",
+        SayError::MessageTooLong => "Message is too long",
+        SayError::DisallowedCharacters => "Message contains disallowed characters",
+        SayError::Unauthenticated => "You must authenticate first",
+        SayError::ConversationDoesntExist => "Conversation doesn't exist",
+        SayError::InvalidConversation => "Conversation ID is invalid",
+        SayError::NotInvolved => "You are not in this conversation",
+        SayError::TooManyAttachments => "Too many attachments",
+        SayError::InvalidAttachment => "Attachment doesn't exist",
+        SayError::ReplyDoesntExist => "Message being replied to doesn't exist in this conversation",
+    }
+}
+
+fn schedule_error_msg(e: ScheduleError) -> ServerMsg {
+    let error_msg = match e {
+        ScheduleError::Say(e) => say_error_message(e),
+        ScheduleError::Unauthenticated => "You must authenticate first",
+        ScheduleError::InvalidConversation => "Conversation ID is invalid",
+        ScheduleError::SendAtInPast => "send_at must be in the future",
+        ScheduleError::SendAtTooFar => "send_at can be at most a year away",
+        ScheduleError::TooManyScheduled => "You have too many scheduled messages",
+        ScheduleError::InvalidScheduleId => "Schedule ID is invalid",
+        ScheduleError::ScheduleDoesntExist => "Scheduled message doesn't exist",
+    };
+    ServerMsg::Error {
+        message: error_msg.to_string(),
+    }
+}
+
+fn react(
+    router_state: &mut RouterState,
+    client_id: u64,
+    tx: &UnboundedSender<Message>,
+    message_id: u64,
+    emoji: String,
+    reacted: bool,
+) {
+    if let Err(e) = handle_react_command(router_state, client_id, message_id, &emoji) {
+        let error_msg = match e {
+            ReactError::Unauthenticated => "You must authenticate first",
+            ReactError::MessageDoesntExist => "Message doesn't exist",
+            ReactError::NotInvolved => "You are not in this conversation",
+            ReactError::MessageDeleted => "Message has been deleted",
+            ReactError::InvalidEmoji => "Invalid emoji",
+        };
+        router_state.send_or_disconnect_server_msg(
+            client_id,
+            tx,
+            &ServerMsg::Error {
+                message: error_msg.to_string(),
+            },
+        );
+        return;
+    }
+
+    let Some(user_uuid) = router_state.connection_to_user.get(&client_id).copied() else {
+        return;
+    };
+    router_state.set_reaction(message_id, user_uuid, emoji, reacted);
+}
+
+fn send_receipt_error(
+    router_state: &mut RouterState,
+    client_id: u64,
+    tx: &UnboundedSender<Message>,
+    e: ReceiptError,
+) {
+    let error_msg = match e {
+        ReceiptError::Unauthenticated => "You must authenticate first",
+        ReceiptError::ConversationDoesntExist => "Conversation doesn't exist",
+        ReceiptError::InvalidConversation => "Conversation ID is invalid",
+        ReceiptError::UnknownMessage => "Message doesn't exist in this conversation",
+        ReceiptError::NotInvolved => "You are not in this conversation",
+    };
+    router_state.send_or_disconnect_server_msg(
+        client_id,
+        tx,
+        &ServerMsg::Error {
+            message: error_msg.to_string(),
+        },
+    );
+}
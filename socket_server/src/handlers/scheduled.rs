@@ -0,0 +1,55 @@
+use uuid::Uuid;
+
+use crate::{
+    protocol::ServerMsg,
+    state::RouterState,
+    text::{TextError, normalize_message},
+};
+
+pub fn handle_scheduled_say_event(router_state: &mut RouterState, schedule_id: Uuid) {
+    // Cancelled since the scheduler last heard about it
+    let Some(scheduled) = router_state.take_scheduled(schedule_id) else {
+        return;
+    };
+
+    // Things may have changed since it was scheduled
+    let still_involved = router_state
+        .conversations
+        .get(&scheduled.conversation_id)
+        .is_some_and(|c| c.participants.contains(&scheduled.sender_id));
+    if !still_involved || router_state.is_deleted_user(&scheduled.sender_id) {
+        return;
+    }
+
+    // Same text rules as Say, the limits may have changed since (MAX_MESSAGE_GRAPHEMES on a restart)
+    let message = match normalize_message(&scheduled.message, router_state.max_message_graphemes) {
+        Ok(message) => message,
+        Err(e) => {
+            let reason = match e {
+                TextError::Empty => "Message is empty",
+                TextError::TooLong => "Message is too long",
+                TextError::DisallowedCharacter => "Message contains disallowed characters",
+            };
+            // Nobody else would ever find out it didn't send
+            let msg = ServerMsg::ScheduledFailed {
+                id: schedule_id.to_string(),
+                conversation: scheduled.conversation_id.to_string(),
+                reason: reason.to_string(),
+            };
+            for connection_id in router_state.connections_for_user(&scheduled.sender_id) {
+                if let Some(tx) = router_state.connections.get(&connection_id).cloned() {
+                    router_state.send_or_disconnect_server_msg(connection_id, &tx, &msg);
+                }
+            }
+            return;
+        }
+    };
+
+    router_state.post_message(
+        scheduled.conversation_id,
+        scheduled.sender_id,
+        message,
+        None,
+        vec![],
+    );
+}
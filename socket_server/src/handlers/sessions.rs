@@ -0,0 +1,34 @@
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::{persistence::Write, state::RouterState};
+
+pub fn handle_revoke_sessions_event(
+    router_state: &mut RouterState,
+    user_id: Uuid,
+    session_version: i32,
+) {
+    router_state.revoke_sessions(user_id, session_version);
+}
+
+pub fn handle_user_deleted_event(
+    router_state: &mut RouterState,
+    user_id: Uuid,
+    reply: oneshot::Sender<()>,
+) {
+    router_state.delete_user(user_id);
+    // Queued after everything delete_user wrote, so once this is stored the rest is too
+    router_state
+        .store
+        .write_acked(Write::SaveDeletedUser { user_id }, reply);
+}
+
+pub fn handle_authorize_session_event(
+    router_state: &mut RouterState,
+    user_id: Uuid,
+    session_version: i32,
+    reply: oneshot::Sender<bool>,
+) {
+    // The HTTP side may have given up waiting, nothing to do about it
+    let _ = reply.send(!router_state.is_session_revoked(&user_id, session_version));
+}
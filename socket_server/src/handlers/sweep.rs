@@ -0,0 +1,9 @@
+use chrono::Utc;
+use tokio::time::Instant;
+
+use crate::state::RouterState;
+
+pub fn handle_sweep_event(router_state: &mut RouterState) {
+    router_state.purge_expired_messages(Utc::now());
+    router_state.rate_limiter.prune(Instant::now());
+}
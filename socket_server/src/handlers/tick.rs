@@ -0,0 +1,7 @@
+use tokio::time::Instant;
+
+use crate::state::RouterState;
+
+pub fn handle_tick_event(router_state: &mut RouterState) {
+    router_state.expire_typing(Instant::now());
+}
@@ -0,0 +1,160 @@
+use std::time::SystemTime;
+
+use axum::{
+    Json,
+    body::Bytes,
+    extract::{Path, State},
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+    },
+    response::IntoResponse,
+};
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::{
+    attachment::{ALLOWED_MIME_TYPES, Attachment, sniff_mime_type},
+    http::{HttpState, authenticated_user},
+    protocol::Event,
+};
+
+/// Store an uploaded file, the returned id can then be sent with a Say
+pub async fn upload_attachment(
+    State(state): State<HttpState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let owner = match authenticated_user(&state, &headers).await {
+        Ok(u) => u,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let mime = headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.split(';').next().unwrap_or("").trim().to_lowercase())
+        .unwrap_or_default();
+    if !ALLOWED_MIME_TYPES.contains(&mime.as_str()) {
+        return (StatusCode::UNSUPPORTED_MEDIA_TYPE, "File type not allowed").into_response();
+    }
+
+    if body.is_empty() {
+        return (StatusCode::BAD_REQUEST, "Empty file").into_response();
+    }
+    // Nothing over MAX_ATTACHMENT_BYTES gets this far, DefaultBodyLimit already answered with a 413
+    // The header is only the client's word for it, so the bytes have to agree
+    if sniff_mime_type(&body) != Some(mime.as_str()) {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "File contents don't match its type",
+        )
+            .into_response();
+    }
+
+    let sha256 = format!("{:x}", Sha256::digest(&body));
+    let path = state.attachment_dir.join(&sha256);
+
+    // Content addressed, so if we already have these bytes there's nothing to write
+    // Bumping its mtime keeps the blob sweeper off it until the router knows about this upload
+    let reused = match tokio::fs::OpenOptions::new().append(true).open(&path).await {
+        Ok(file) => file
+            .into_std()
+            .await
+            .set_modified(SystemTime::now())
+            .is_ok(),
+        Err(_) => false,
+    };
+    if !reused {
+        let tmp_path = state
+            .attachment_dir
+            .join(format!("{sha256}.{}.tmp", Uuid::new_v4()));
+        if let Err(e) = tokio::fs::write(&tmp_path, &body).await {
+            eprintln!("failed to write attachment: {e}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to store file").into_response();
+        }
+        if let Err(e) = tokio::fs::rename(&tmp_path, &path).await {
+            eprintln!("failed to move attachment into place: {e}");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to store file").into_response();
+        }
+    }
+
+    let file_name = headers
+        .get("x-file-name")
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.chars().filter(|c| !c.is_control()).take(255).collect());
+
+    let attachment = Attachment {
+        id: Uuid::new_v4(),
+        owner,
+        sha256,
+        size: body.len(),
+        mime,
+        file_name,
+        uploaded_at: Utc::now(),
+        conversation_id: None,
+    };
+
+    if state
+        .tx
+        .send(Event::AttachmentUploaded {
+            attachment: attachment.clone(),
+        })
+        .is_err()
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Router unavailable").into_response();
+    }
+
+    (StatusCode::CREATED, Json(attachment)).into_response()
+}
+
+/// Download a file, only for its uploader and the participants of the conversation it was sent to
+pub async fn download_attachment(
+    State(state): State<HttpState>,
+    Path(attachment_id): Path<Uuid>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match authenticated_user(&state, &headers).await {
+        Ok(u) => u,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    // The router owns conversations, so it's the one that gets to say yes
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if state
+        .tx
+        .send(Event::AuthorizeAttachment {
+            user_id,
+            attachment_id,
+            reply: reply_tx,
+        })
+        .is_err()
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Router unavailable").into_response();
+    }
+
+    // Not found rather than forbidden, no need to confirm the id exists
+    let attachment = match reply_rx.await {
+        Ok(Some(a)) => a,
+        _ => return (StatusCode::NOT_FOUND, "Attachment not found").into_response(),
+    };
+
+    let bytes = match tokio::fs::read(state.attachment_dir.join(&attachment.sha256)).await {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("failed to read attachment {}: {e}", attachment.id);
+            return (StatusCode::NOT_FOUND, "Attachment not found").into_response();
+        }
+    };
+
+    let mut response_headers = HeaderMap::new();
+    if let Ok(mime) = HeaderValue::from_str(&attachment.mime) {
+        response_headers.insert(CONTENT_TYPE, mime);
+    }
+    response_headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    response_headers.insert(CONTENT_DISPOSITION, HeaderValue::from_static("attachment"));
+
+    (StatusCode::OK, response_headers, bytes).into_response()
+}
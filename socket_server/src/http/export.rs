@@ -0,0 +1,81 @@
+use std::convert::Infallible;
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{
+        HeaderMap, HeaderValue, StatusCode,
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE, X_CONTENT_TYPE_OPTIONS},
+    },
+    response::IntoResponse,
+};
+use futures_util::stream;
+use serde::Deserialize;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::{
+    export::{ExportFormat, export_lines},
+    http::{HttpState, authenticated_user},
+    protocol::Event,
+};
+
+#[derive(Deserialize)]
+pub struct ExportParams {
+    format: Option<String>, // jsonl (default) or text
+}
+
+/// Export every message of a conversation you're in, as JSON Lines or a plain text transcript
+pub async fn export_conversation(
+    State(state): State<HttpState>,
+    Path(conversation_id): Path<Uuid>,
+    Query(params): Query<ExportParams>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let user_id = match authenticated_user(&state, &headers).await {
+        Ok(u) => u,
+        Err(rejection) => return rejection.into_response(),
+    };
+
+    let Some(format) = ExportFormat::parse(params.format.as_deref()) else {
+        return (StatusCode::BAD_REQUEST, "Format must be jsonl or text").into_response();
+    };
+
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if state
+        .tx
+        .send(Event::ExportConversation {
+            user_id,
+            conversation_id,
+            reply: reply_tx,
+        })
+        .is_err()
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Router unavailable").into_response();
+    }
+
+    // Same as attachments, not found whether it doesn't exist or you aren't in it
+    let (conversation, names) = match reply_rx.await {
+        Ok(Some(c)) => c,
+        _ => return (StatusCode::NOT_FOUND, "Conversation not found").into_response(),
+    };
+
+    let mut response_headers = HeaderMap::new();
+    response_headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    response_headers.insert(X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+    if let Ok(disposition) = HeaderValue::from_str(&format!(
+        "attachment; filename=\"conversation-{conversation_id}.{}\"",
+        format.extension()
+    )) {
+        response_headers.insert(CONTENT_DISPOSITION, disposition);
+    }
+
+    // The router handed over a copy, but its lines are only serialized as the body is sent
+    let lines = export_lines(conversation, names, format);
+    let body = Body::from_stream(stream::iter(lines.map(Ok::<_, Infallible>)));
+
+    (StatusCode::OK, response_headers, body).into_response()
+}
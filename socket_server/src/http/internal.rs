@@ -0,0 +1,110 @@
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    response::IntoResponse,
+};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tokio::sync::oneshot;
+use uuid::Uuid;
+
+use crate::{directory::display_name, http::HttpState, protocol::Event};
+
+#[derive(Deserialize)]
+pub struct RevokeSessionsRequest {
+    session_version: i32,
+}
+
+#[derive(Deserialize)]
+pub struct ProfileUpdatedRequest {
+    username: String,
+    display_name: Option<String>,
+}
+
+// Only auth_service knows the secret, without one these routes don't exist
+fn is_internal(state: &HttpState, headers: &HeaderMap) -> Result<(), StatusCode> {
+    let Some(secret) = &state.internal_secret else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if !bool::from(token.as_bytes().ct_eq(secret.as_bytes())) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(())
+}
+
+/// Close a user's connections made with tokens older than session_version, and refuse those tokens from now on
+pub async fn revoke_sessions(
+    State(state): State<HttpState>,
+    Path(user_id): Path<Uuid>,
+    headers: HeaderMap,
+    Json(payload): Json<RevokeSessionsRequest>,
+) -> impl IntoResponse {
+    if let Err(status) = is_internal(&state, &headers) {
+        return status.into_response();
+    }
+    if state
+        .tx
+        .send(Event::RevokeSessions {
+            user_id,
+            session_version: payload.session_version,
+        })
+        .is_err()
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Router unavailable").into_response();
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// The account was deleted in auth_service, close its connections and take it out of its conversations
+pub async fn user_deleted(
+    State(state): State<HttpState>,
+    Path(user_id): Path<Uuid>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if let Err(status) = is_internal(&state, &headers) {
+        return status.into_response();
+    }
+    let (reply_tx, reply_rx) = oneshot::channel();
+    if state
+        .tx
+        .send(Event::UserDeleted {
+            user_id,
+            reply: reply_tx,
+        })
+        .is_err()
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Router unavailable").into_response();
+    }
+    // auth_service keeps asking until this is a 204, so only answer once it's stored
+    if reply_rx.await.is_err() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Failed to store deletion").into_response();
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
+
+/// The user changed their display name in auth_service, use it for their messages from now on
+pub async fn profile_updated(
+    State(state): State<HttpState>,
+    Path(user_id): Path<Uuid>,
+    headers: HeaderMap,
+    Json(payload): Json<ProfileUpdatedRequest>,
+) -> impl IntoResponse {
+    if let Err(status) = is_internal(&state, &headers) {
+        return status.into_response();
+    }
+    let name = display_name(&payload.username, payload.display_name.as_deref());
+    if state
+        .tx
+        .send(Event::ProfileUpdated { user_id, name })
+        .is_err()
+    {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Router unavailable").into_response();
+    }
+    StatusCode::NO_CONTENT.into_response()
+}
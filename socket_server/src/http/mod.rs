@@ -0,0 +1,123 @@
+pub mod attachments;
+pub mod export;
+pub mod internal;
+
+use std::{env, path::PathBuf};
+
+use axum::{
+    Router,
+    extract::DefaultBodyLimit,
+    http::{HeaderMap, StatusCode, header::AUTHORIZATION},
+    routing::{get, post},
+};
+use jsonwebtoken::DecodingKey;
+use tokio::sync::{mpsc::UnboundedSender, oneshot};
+use uuid::Uuid;
+
+use crate::{
+    attachment::{DEFAULT_MAX_ATTACHMENT_BYTES, attachment_dir},
+    commands::authenticate::decode_claims,
+    protocol::Event,
+};
+
+// The websocket side can't do uploads, so this runs alongside it and talks to the router over the same channel
+#[derive(Clone)]
+pub struct HttpState {
+    pub tx: UnboundedSender<Event>,
+    pub decoding_key: DecodingKey,
+    pub attachment_dir: PathBuf,
+    pub internal_secret: Option<String>, // Shared with auth_service for the /internal routes
+}
+
+pub async fn serve_http(tx: UnboundedSender<Event>) {
+    let addr = env::var("HTTP_ADDR").unwrap_or("127.0.0.1:9902".to_string());
+    let attachment_dir = attachment_dir();
+    let max_attachment_bytes = env::var("MAX_ATTACHMENT_BYTES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_ATTACHMENT_BYTES);
+
+    if let Err(e) = tokio::fs::create_dir_all(&attachment_dir).await {
+        eprintln!("failed to create attachment dir: {e}");
+        return;
+    }
+
+    let state = HttpState {
+        tx,
+        decoding_key: DecodingKey::from_secret(
+            env::var("JWT_SECRET").expect("no JWT_SECRET set").as_ref(),
+        ),
+        attachment_dir,
+        internal_secret: env::var("INTERNAL_SECRET").ok().filter(|s| !s.is_empty()),
+    };
+
+    let app = Router::new()
+        .route("/attachments", post(attachments::upload_attachment))
+        .route(
+            "/attachments/{attachment_id}",
+            get(attachments::download_attachment),
+        )
+        .route(
+            "/conversations/{conversation_id}/export",
+            get(export::export_conversation),
+        )
+        .route(
+            "/internal/users/{user_id}/revoke_sessions",
+            post(internal::revoke_sessions),
+        )
+        .route(
+            "/internal/users/{user_id}/deleted",
+            post(internal::user_deleted),
+        )
+        .route(
+            "/internal/users/{user_id}/profile",
+            post(internal::profile_updated),
+        )
+        .layer(DefaultBodyLimit::max(max_attachment_bytes))
+        .with_state(state);
+
+    let listener = match tokio::net::TcpListener::bind(&addr).await {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("failed to bind http listener: {e}");
+            return;
+        }
+    };
+    println!("HTTP listening on {addr}");
+    if let Err(e) = axum::serve(listener, app).await {
+        eprintln!("http server stopped: {e}");
+    }
+}
+
+const INVALID_TOKEN: (StatusCode, &str) = (StatusCode::UNAUTHORIZED, "Invalid or missing token");
+const ROUTER_UNAVAILABLE: (StatusCode, &str) =
+    (StatusCode::SERVICE_UNAVAILABLE, "Router unavailable");
+
+// Same tokens as the websocket Authenticate command, just in a header
+pub async fn authenticated_user(
+    state: &HttpState,
+    headers: &HeaderMap,
+) -> Result<Uuid, (StatusCode, &'static str)> {
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(INVALID_TOKEN)?;
+    let claims = decode_claims(&state.decoding_key, token).map_err(|_| INVALID_TOKEN)?;
+
+    // Revoked sessions and deleted accounts are only known to the router
+    let (reply_tx, reply_rx) = oneshot::channel();
+    state
+        .tx
+        .send(Event::AuthorizeSession {
+            user_id: claims.user.id,
+            session_version: claims.sv,
+            reply: reply_tx,
+        })
+        .map_err(|_| ROUTER_UNAVAILABLE)?;
+    match reply_rx.await {
+        Ok(true) => Ok(claims.user.id),
+        Ok(false) => Err(INVALID_TOKEN),
+        Err(_) => Err(ROUTER_UNAVAILABLE),
+    }
+}
@@ -1,14 +1,33 @@
+mod attachment;
 mod commands;
 mod conversation;
+mod db;
+mod directory;
+mod export;
 mod handlers;
+mod http;
+mod mentions;
+mod message;
+mod persistence;
+mod presence;
 mod protocol;
+mod rate_limit;
 mod router;
+mod scheduler;
 mod send;
 mod state;
+mod text;
 
+use attachment::attachment_dir;
+use db::connect;
+use directory::AuthDirectory;
+use http::serve_http;
 use protocol::Event;
-use router::{handle_connection, handle_router};
-use std::io::Error;
+use router::{
+    handle_blob_sweeper, handle_connection, handle_router, handle_sweeper, handle_ticker,
+};
+use scheduler::{ScheduleOp, handle_scheduler};
+use std::{env, io::Error};
 use tokio::{
     net::TcpListener,
     sync::mpsc::{self},
@@ -18,6 +37,32 @@ use tokio::{
 async fn main() -> Result<(), Error> {
     dotenvy::dotenv().ok();
 
+    // `socket_server import <file>` restores an export instead of starting the server
+    let args: Vec<String> = env::args().collect();
+    if args.get(1).map(String::as_str) == Some("import") {
+        let Some(path) = args.get(2) else {
+            eprintln!("usage: socket_server import <export.jsonl>");
+            std::process::exit(2);
+        };
+        let db = match connect(&env::var("DATABASE_URL").expect("no DATABASE_URL set")).await {
+            Ok(db) => db,
+            Err(e) => {
+                eprintln!("failed to connect to Postgres: {e}");
+                std::process::exit(1);
+            }
+        };
+        match export::import_file(&db, path).await {
+            Ok((conversation_id, count)) => {
+                println!("Imported {count} messages into conversation {conversation_id}")
+            }
+            Err(e) => {
+                eprintln!("import failed: {e}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
     let (tx, rx) = mpsc::unbounded_channel::<Event>(); // Don't set a fixed size of messages
     // In the future we should really use a bounded channel and handle back pressure... :/
 
@@ -26,7 +71,32 @@ async fn main() -> Result<(), Error> {
         .expect("failed to bind socket");
     println!("Listening on 127.0.0.1:9901");
 
-    tokio::spawn(handle_router(rx));
+    // Persistence is optional, without it everything is gone on restart
+    let db = match env::var("DATABASE_URL") {
+        Ok(url) => match connect(&url).await {
+            Ok(db) => Some(db),
+            // Still better to chat without history than not at all
+            Err(e) => {
+                eprintln!("failed to connect to Postgres, keeping everything in memory: {e}");
+                None
+            }
+        },
+        Err(_) => None,
+    };
+
+    let (schedule_tx, schedule_rx) = mpsc::unbounded_channel::<ScheduleOp>();
+
+    tokio::spawn(handle_router(
+        rx,
+        db,
+        schedule_tx,
+        AuthDirectory::from_env(tx.clone()),
+    ));
+    tokio::spawn(handle_scheduler(tx.clone(), schedule_rx));
+    tokio::spawn(handle_ticker(tx.clone()));
+    tokio::spawn(handle_sweeper(tx.clone()));
+    tokio::spawn(handle_blob_sweeper(tx.clone(), attachment_dir()));
+    tokio::spawn(serve_http(tx.clone()));
 
     let mut next_id: u64 = 0;
 
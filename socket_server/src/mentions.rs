@@ -0,0 +1,90 @@
+use uuid::Uuid;
+
+#[derive(Debug, PartialEq)]
+pub enum Mention {
+    Username(String),
+    Id(Uuid),
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// Picks out `@username` and `@<uuid>`, the latter for people we don't know the name of
+// An @ stuck to the end of a word (emails and the like) isn't a mention
+pub fn parse_mentions(message: &str) -> Vec<Mention> {
+    let mut mentions: Vec<Mention> = vec![];
+    let mut previous: Option<char> = None;
+
+    for (index, c) in message.char_indices() {
+        let at_word_start = previous.is_none_or(|p| !is_username_char(p) && p != '@');
+        previous = Some(c);
+        if c != '@' || !at_word_start {
+            continue;
+        }
+
+        let rest = &message[index + 1..];
+        if let Some(inner) = rest.strip_prefix('<') {
+            if let Some(end) = inner.find('>')
+                && let Ok(id) = Uuid::try_parse(&inner[..end])
+            {
+                mentions.push(Mention::Id(id));
+            }
+            continue;
+        }
+
+        let end = rest.find(|c| !is_username_char(c)).unwrap_or(rest.len());
+        if end > 0 {
+            mentions.push(Mention::Username(rest[..end].to_lowercase()));
+        }
+    }
+
+    mentions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn username(name: &str) -> Mention {
+        Mention::Username(name.to_string())
+    }
+
+    #[test]
+    fn finds_usernames_anywhere_a_word_starts() {
+        assert_eq!(
+            parse_mentions("@alice hi (@Bob_2), @carol!"),
+            [username("alice"), username("bob_2"), username("carol")]
+        );
+    }
+
+    #[test]
+    fn ignores_at_signs_inside_words() {
+        assert!(parse_mentions("mail me at bob@example.com").is_empty());
+        assert!(parse_mentions("@@bob").is_empty());
+        assert!(parse_mentions("just an @ sign").is_empty());
+    }
+
+    #[test]
+    fn finds_ids_in_angle_brackets() {
+        let id = Uuid::new_v4();
+        assert_eq!(
+            parse_mentions(&format!("hey @<{id}> and @dave")),
+            [Mention::Id(id), username("dave")]
+        );
+    }
+
+    #[test]
+    fn skips_bracketed_mentions_that_arent_ids() {
+        assert!(parse_mentions("@<not-an-id> @<unclosed").is_empty());
+    }
+
+    #[test]
+    fn keeps_repeats() {
+        // Deduplicating is the caller's job, once they're resolved to users
+        assert_eq!(
+            parse_mentions("@eve @EVE"),
+            [username("eve"), username("eve")]
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::attachment::Attachment;
+
+// Also how it's persisted, see persistence::Write::SaveMessage
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub id: u64, // Server assigned and increasing, so "up to" comparisons work
+    pub conversation_id: Uuid,
+    pub from: Uuid,
+    pub message: String,
+    pub sent_at: DateTime<Utc>,
+    pub reply_to: Option<u64>,
+    pub thread_root: Option<u64>, // Replies to replies still belong to the first message's thread
+    pub mentions: Vec<Uuid>,
+    pub attachments: Vec<Attachment>,
+    pub edited_at: Option<DateTime<Utc>>,
+    pub edits: Vec<MessageRevision>, // Previous versions, oldest first
+    pub deleted_at: Option<DateTime<Utc>>, // Tombstone, the content is gone but the id stays
+    pub reactions: BTreeMap<String, BTreeSet<Uuid>>, // emoji -> who reacted with it
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct MessageRevision {
+    pub message: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub written_at: DateTime<Utc>,
+}
+
+impl ChatMessage {
+    pub fn new(id: u64, conversation_id: Uuid, from: Uuid, message: String) -> ChatMessage {
+        ChatMessage {
+            id,
+            conversation_id,
+            from,
+            message,
+            sent_at: Utc::now(),
+            reply_to: None,
+            thread_root: None,
+            mentions: vec![],
+            attachments: vec![],
+            edited_at: None,
+            edits: vec![],
+            deleted_at: None,
+            reactions: BTreeMap::new(),
+            expires_at: None,
+        }
+    }
+
+    pub fn in_thread(&self, root_id: u64) -> bool {
+        self.id == root_id || self.thread_root == Some(root_id)
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+
+    pub fn edit(&mut self, message: String) {
+        let now = Utc::now();
+        let previous = std::mem::replace(&mut self.message, message);
+        self.edits.push(MessageRevision {
+            message: previous,
+            written_at: self.edited_at.unwrap_or(self.sent_at),
+        });
+        self.edited_at = Some(now);
+    }
+
+    // Deleting drops the content and its edit history, only the tombstone is kept
+    pub fn delete(&mut self) {
+        self.message.clear();
+        self.edits.clear();
+        self.reactions.clear();
+        self.mentions.clear();
+        self.attachments.clear();
+        self.deleted_at = Some(Utc::now());
+    }
+
+    // Returns false if nothing changed, so we don't broadcast no-ops
+    pub fn set_reaction(&mut self, user_id: Uuid, emoji: &str, reacted: bool) -> bool {
+        if reacted {
+            return self
+                .reactions
+                .entry(emoji.to_string())
+                .or_default()
+                .insert(user_id);
+        }
+
+        let Some(users) = self.reactions.get_mut(emoji) else {
+            return false;
+        };
+        let removed = users.remove(&user_id);
+        if users.is_empty() {
+            self.reactions.remove(emoji);
+        }
+        removed
+    }
+
+    pub fn reaction_count(&self, emoji: &str) -> usize {
+        self.reactions.get(emoji).map(|u| u.len()).unwrap_or(0)
+    }
+
+    // names is RouterState::names, senders we don't know a name for just get their id
+    pub fn view(&self, names: &HashMap<Uuid, String>) -> MessageView {
+        MessageView {
+            id: self.id,
+            conversation: self.conversation_id.to_string(),
+            from: self.from.to_string(),
+            from_name: names.get(&self.from).cloned(),
+            message: self.message.clone(),
+            sent_at: self.sent_at,
+            reply_to: self.reply_to,
+            thread_root: self.thread_root,
+            mentions: self.mentions.iter().map(|u| u.to_string()).collect(),
+            attachments: self.attachments.clone(),
+            edited_at: self.edited_at,
+            edits: self.edits.clone(),
+            deleted: self.is_deleted(),
+            expires_at: self.expires_at,
+            reactions: self
+                .reactions
+                .iter()
+                .map(|(emoji, users)| ReactionView {
+                    emoji: emoji.clone(),
+                    count: users.len(),
+                    user_ids: users.iter().map(|u| u.to_string()).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+// What clients get to see of a message, both live and in history
+#[derive(Serialize, Debug)]
+pub struct MessageView {
+    pub id: u64,
+    pub conversation: String,
+    pub from: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from_name: Option<String>, // Display name, or the username if they haven't set one
+    pub message: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub sent_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_root: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    #[serde(
+        with = "chrono::serde::ts_seconds_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub edited_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub edits: Vec<MessageRevision>,
+    pub deleted: bool,
+    #[serde(
+        with = "chrono::serde::ts_seconds_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub expires_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<ReactionView>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReactionView {
+    pub emoji: String,
+    pub count: usize,
+    pub user_ids: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptStatus {
+    Delivered,
+    Read,
+}
+
+// How far each participant has got through a conversation
+#[derive(Clone, Copy, Default)]
+pub struct Receipt {
+    pub delivered_up_to: u64,
+    pub read_up_to: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReceiptView {
+    pub user_id: String,
+    pub delivered_up_to: u64,
+    pub read_up_to: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(text: &str) -> ChatMessage {
+        ChatMessage::new(1, Uuid::new_v4(), Uuid::new_v4(), text.to_string())
+    }
+
+    #[test]
+    fn edit_keeps_previous_versions_oldest_first() {
+        let mut m = message("first");
+        m.edit("second".to_string());
+        let first_edit = m.edited_at.unwrap();
+        m.edit("third".to_string());
+
+        assert_eq!(m.message, "third");
+        let previous: Vec<&str> = m.edits.iter().map(|e| e.message.as_str()).collect();
+        assert_eq!(previous, ["first", "second"]);
+        // Each revision is dated from when it was written, not when it was replaced
+        assert_eq!(m.edits[0].written_at, m.sent_at);
+        assert_eq!(m.edits[1].written_at, first_edit);
+    }
+
+    #[test]
+    fn delete_leaves_only_a_tombstone() {
+        let mut m = message("secret @bob");
+        m.edit("still secret".to_string());
+        m.mentions.push(Uuid::new_v4());
+        m.set_reaction(Uuid::new_v4(), "👍", true);
+        m.delete();
+
+        assert!(m.is_deleted());
+        assert!(m.message.is_empty());
+        assert!(m.edits.is_empty());
+        assert!(m.mentions.is_empty());
+        assert!(m.reactions.is_empty());
+        assert!(m.attachments.is_empty());
+        assert_eq!(m.id, 1);
+    }
+
+    #[test]
+    fn set_reaction_only_reports_changes() {
+        let mut m = message("hi");
+        let alice = Uuid::new_v4();
+        let bob = Uuid::new_v4();
+
+        assert!(m.set_reaction(alice, "👍", true));
+        assert!(!m.set_reaction(alice, "👍", true));
+        assert!(m.set_reaction(bob, "👍", true));
+        assert_eq!(m.reaction_count("👍"), 2);
+
+        assert!(!m.set_reaction(alice, "🎉", false));
+        assert!(m.set_reaction(alice, "👍", false));
+        assert!(!m.set_reaction(alice, "👍", false));
+        assert_eq!(m.reaction_count("👍"), 1);
+    }
+
+    #[test]
+    fn set_reaction_drops_emoji_nobody_uses() {
+        let mut m = message("hi");
+        let alice = Uuid::new_v4();
+        m.set_reaction(alice, "👍", true);
+        m.set_reaction(alice, "👍", false);
+        assert!(m.reactions.is_empty());
+        assert_eq!(m.reaction_count("👍"), 0);
+    }
+}
@@ -0,0 +1,516 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{Connection, PgConnection, prelude::FromRow};
+use tokio::sync::{
+    mpsc::{self, UnboundedReceiver, UnboundedSender},
+    oneshot,
+};
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+use crate::{
+    conversation::Conversation,
+    db::DbPool,
+    message::{ChatMessage, Receipt},
+    protocol::ServerMsg,
+    scheduler::ScheduledMessage,
+    send::send_server_msg,
+};
+
+pub enum Write {
+    SaveConversation {
+        id: Uuid,
+        participants: Vec<Uuid>,
+        message_ttl: Option<Duration>,
+    },
+    SaveScheduled(ScheduledMessage),
+    DeleteScheduled {
+        id: Uuid,
+    },
+    SaveMessage(ChatMessage),
+    DeleteMessages {
+        ids: Vec<u64>,
+    },
+    SaveReceipt {
+        conversation_id: Uuid,
+        user_id: Uuid,
+        receipt: Receipt,
+    },
+    DeleteReceipts {
+        user_id: Uuid,
+    },
+    SaveDeletedUser {
+        user_id: Uuid,
+    },
+    SaveUserName {
+        user_id: Uuid,
+        name: String,
+    },
+    DeleteUserName {
+        user_id: Uuid,
+    },
+}
+
+pub struct SearchQuery {
+    pub user_id: Uuid,
+    pub query: String,
+    pub conversation_id: Option<Uuid>,
+    pub limit: usize,
+    pub cursor: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchHit {
+    pub conversation: String,
+    pub message_id: u64,
+    pub from: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub sent_at: DateTime<Utc>,
+    pub snippet: String, // HTML escaped, with the matches wrapped in <mark>
+}
+
+// The router can't wait on the database, so writes get queued up here and done in order by handle_writer
+// Without a DATABASE_URL it's a no-op and everything only lives in memory
+#[derive(Clone, Default)]
+pub struct Store {
+    tx: Option<UnboundedSender<(Write, Option<oneshot::Sender<()>>)>>,
+    db: Option<DbPool>, // Only for reads that can't be served from memory, like search
+}
+
+impl Store {
+    pub fn new(db: Option<DbPool>) -> Store {
+        let Some(db) = db else {
+            return Store { tx: None, db: None };
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(handle_writer(db.clone(), rx));
+        Store {
+            tx: Some(tx),
+            db: Some(db),
+        }
+    }
+
+    pub fn is_persistent(&self) -> bool {
+        self.db.is_some()
+    }
+
+    pub fn write(&self, write: Write) {
+        if let Some(tx) = &self.tx
+            && tx.send((write, None)).is_err()
+        {
+            eprintln!("persistence writer has stopped, dropping write");
+        }
+    }
+
+    // Like write, but done only fires once it's committed, and is dropped if it fails
+    // In memory there's nothing to wait for so it fires straight away
+    pub fn write_acked(&self, write: Write, done: oneshot::Sender<()>) {
+        let Some(tx) = &self.tx else {
+            let _ = done.send(());
+            return;
+        };
+        if tx.send((write, Some(done))).is_err() {
+            eprintln!("persistence writer has stopped, dropping write");
+        }
+    }
+
+    pub fn save_conversation(&self, conversation: &Conversation) {
+        self.write(Write::SaveConversation {
+            id: conversation.id,
+            participants: conversation.participants.clone(),
+            message_ttl: conversation.message_ttl,
+        });
+    }
+
+    pub fn save_message(&self, message: &ChatMessage) {
+        self.write(Write::SaveMessage(message.clone()));
+    }
+
+    // Runs off the router and answers the connection directly, the results can't change router state
+    pub fn search(&self, search: SearchQuery, tx: UnboundedSender<Message>) {
+        let Some(db) = self.db.clone() else {
+            return;
+        };
+        tokio::spawn(async move {
+            let msg = match search_messages(&db, &search).await {
+                Ok((hits, next_cursor)) => ServerMsg::SearchResults {
+                    query: search.query,
+                    hits,
+                    next_cursor,
+                },
+                Err(e) => {
+                    eprintln!("search failed: {e}");
+                    ServerMsg::Error {
+                        message: "Search failed".to_string(),
+                    }
+                }
+            };
+            // If they've gone away in the meantime the router will clean up after them
+            let _ = send_server_msg(&tx, &msg);
+        });
+    }
+}
+
+async fn handle_writer(
+    db: DbPool,
+    mut rx: UnboundedReceiver<(Write, Option<oneshot::Sender<()>>)>,
+) {
+    while let Some((write, done)) = rx.recv().await {
+        let res = match db.acquire().await {
+            Ok(mut conn) => apply_write(&mut conn, write).await,
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(()) => {
+                if let Some(done) = done {
+                    let _ = done.send(());
+                }
+            }
+            Err(e) => eprintln!("failed to persist: {e}"),
+        }
+    }
+}
+
+// Takes a connection rather than the pool so an import can run its writes in one transaction
+pub async fn apply_write(conn: &mut PgConnection, write: Write) -> Result<(), sqlx::Error> {
+    match write {
+        Write::SaveConversation {
+            id,
+            participants,
+            message_ttl,
+        } => {
+            let mut transaction = conn.begin().await?;
+            sqlx::query(
+                "INSERT INTO conversations (id, message_ttl_seconds) VALUES ($1, $2)
+                 ON CONFLICT (id) DO UPDATE SET message_ttl_seconds = EXCLUDED.message_ttl_seconds",
+            )
+            .bind(id)
+            .bind(message_ttl.map(|t| t.as_secs() as i64))
+            .execute(&mut *transaction)
+            .await?;
+            sqlx::query("DELETE FROM conversation_participants WHERE conversation_id = $1")
+                .bind(id)
+                .execute(&mut *transaction)
+                .await?;
+            for user_id in participants {
+                sqlx::query(
+                    "INSERT INTO conversation_participants (conversation_id, user_id) VALUES ($1, $2)",
+                )
+                .bind(id)
+                .bind(user_id)
+                .execute(&mut *transaction)
+                .await?;
+            }
+            transaction.commit().await?;
+        }
+        Write::SaveScheduled(scheduled) => {
+            sqlx::query(
+                "INSERT INTO scheduled_messages (id, conversation_id, sender_id, message, send_at)
+                 VALUES ($1, $2, $3, $4, $5)",
+            )
+            .bind(scheduled.id)
+            .bind(scheduled.conversation_id)
+            .bind(scheduled.sender_id)
+            .bind(&scheduled.message)
+            .bind(scheduled.send_at)
+            .execute(&mut *conn)
+            .await?;
+        }
+        Write::DeleteScheduled { id } => {
+            sqlx::query("DELETE FROM scheduled_messages WHERE id = $1")
+                .bind(id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Write::SaveMessage(message) => {
+            let data =
+                serde_json::to_string(&message).map_err(|e| sqlx::Error::Encode(e.into()))?;
+            let mut transaction = conn.begin().await?;
+            sqlx::query(
+                "INSERT INTO messages (id, conversation_id, sender_id, body, sent_at, deleted_at, expires_at, data)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8::jsonb)
+                 ON CONFLICT (id) DO UPDATE SET
+                    sender_id = EXCLUDED.sender_id,
+                    body = EXCLUDED.body,
+                    deleted_at = EXCLUDED.deleted_at,
+                    expires_at = EXCLUDED.expires_at,
+                    data = EXCLUDED.data",
+            )
+            .bind(message.id as i64)
+            .bind(message.conversation_id)
+            .bind(message.from)
+            .bind(&message.message)
+            .bind(message.sent_at)
+            .bind(message.deleted_at)
+            .bind(message.expires_at)
+            .bind(data)
+            .execute(&mut *transaction)
+            .await?;
+            sqlx::query("UPDATE message_id_high_water SET last_id = GREATEST(last_id, $1)")
+                .bind(message.id as i64)
+                .execute(&mut *transaction)
+                .await?;
+            transaction.commit().await?;
+        }
+        Write::DeleteMessages { ids } => {
+            let ids: Vec<i64> = ids.into_iter().map(|id| id as i64).collect();
+            sqlx::query("DELETE FROM messages WHERE id = ANY($1)")
+                .bind(ids)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Write::SaveReceipt {
+            conversation_id,
+            user_id,
+            receipt,
+        } => {
+            sqlx::query(
+                "INSERT INTO conversation_receipts (conversation_id, user_id, delivered_up_to, read_up_to)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (conversation_id, user_id) DO UPDATE SET
+                    delivered_up_to = EXCLUDED.delivered_up_to,
+                    read_up_to = EXCLUDED.read_up_to",
+            )
+            .bind(conversation_id)
+            .bind(user_id)
+            .bind(receipt.delivered_up_to as i64)
+            .bind(receipt.read_up_to as i64)
+            .execute(&mut *conn)
+            .await?;
+        }
+        Write::DeleteReceipts { user_id } => {
+            sqlx::query("DELETE FROM conversation_receipts WHERE user_id = $1")
+                .bind(user_id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Write::SaveDeletedUser { user_id } => {
+            sqlx::query("INSERT INTO deleted_users (user_id) VALUES ($1) ON CONFLICT DO NOTHING")
+                .bind(user_id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Write::SaveUserName { user_id, name } => {
+            sqlx::query(
+                "INSERT INTO user_names (user_id, name) VALUES ($1, $2)
+                 ON CONFLICT (user_id) DO UPDATE SET name = EXCLUDED.name, updated_at = NOW()",
+            )
+            .bind(user_id)
+            .bind(name)
+            .execute(&mut *conn)
+            .await?;
+        }
+        Write::DeleteUserName { user_id } => {
+            sqlx::query("DELETE FROM user_names WHERE user_id = $1")
+                .bind(user_id)
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(FromRow)]
+struct ConversationRow {
+    id: Uuid,
+    message_ttl_seconds: Option<i64>,
+}
+
+#[derive(FromRow)]
+struct ParticipantRow {
+    conversation_id: Uuid,
+    user_id: Uuid,
+}
+
+#[derive(FromRow)]
+struct ScheduledRow {
+    id: Uuid,
+    conversation_id: Uuid,
+    sender_id: Uuid,
+    message: String,
+    send_at: DateTime<Utc>,
+}
+
+#[derive(FromRow)]
+struct MessageRow {
+    data: String,
+}
+
+#[derive(FromRow)]
+struct ReceiptRow {
+    conversation_id: Uuid,
+    user_id: Uuid,
+    delivered_up_to: i64,
+    read_up_to: i64,
+}
+
+#[derive(FromRow)]
+struct SearchRow {
+    id: i64,
+    conversation_id: Uuid,
+    sender_id: Uuid,
+    sent_at: DateTime<Utc>,
+    snippet: String,
+}
+
+pub async fn load_conversations(db: &DbPool) -> Result<Vec<Conversation>, sqlx::Error> {
+    let rows =
+        sqlx::query_as::<_, ConversationRow>("SELECT id, message_ttl_seconds FROM conversations")
+            .fetch_all(db)
+            .await?;
+    let participants = sqlx::query_as::<_, ParticipantRow>(
+        "SELECT conversation_id, user_id FROM conversation_participants",
+    )
+    .fetch_all(db)
+    .await?;
+
+    let conversations = rows
+        .into_iter()
+        .map(|row| {
+            let mut conversation = Conversation::new(
+                participants
+                    .iter()
+                    .filter(|p| p.conversation_id == row.id)
+                    .map(|p| p.user_id)
+                    .collect(),
+            );
+            conversation.id = row.id;
+            conversation.message_ttl = row
+                .message_ttl_seconds
+                .map(|s| Duration::from_secs(s as u64));
+            conversation
+        })
+        .collect();
+    Ok(conversations)
+}
+
+pub async fn load_scheduled(db: &DbPool) -> Result<Vec<ScheduledMessage>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, ScheduledRow>(
+        "SELECT id, conversation_id, sender_id, message, send_at FROM scheduled_messages",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| ScheduledMessage {
+            id: row.id,
+            conversation_id: row.conversation_id,
+            sender_id: row.sender_id,
+            message: row.message,
+            send_at: row.send_at,
+        })
+        .collect())
+}
+
+// Oldest first, so they go back into their conversations in order
+pub async fn load_messages(db: &DbPool) -> Result<Vec<ChatMessage>, sqlx::Error> {
+    let rows =
+        sqlx::query_as::<_, MessageRow>("SELECT data::text AS data FROM messages ORDER BY id")
+            .fetch_all(db)
+            .await?;
+    let mut messages = vec![];
+    for row in rows {
+        match serde_json::from_str::<ChatMessage>(&row.data) {
+            Ok(message) => messages.push(message),
+            Err(e) => eprintln!("skipping unreadable stored message: {e}"),
+        }
+    }
+    Ok(messages)
+}
+
+pub async fn load_last_message_id(db: &DbPool) -> Result<u64, sqlx::Error> {
+    let (last_id,): (i64,) = sqlx::query_as("SELECT last_id FROM message_id_high_water")
+        .fetch_one(db)
+        .await?;
+    Ok(last_id as u64)
+}
+
+pub async fn load_receipts(db: &DbPool) -> Result<Vec<(Uuid, Uuid, Receipt)>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, ReceiptRow>(
+        "SELECT conversation_id, user_id, delivered_up_to, read_up_to FROM conversation_receipts",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.conversation_id,
+                row.user_id,
+                Receipt {
+                    delivered_up_to: row.delivered_up_to as u64,
+                    read_up_to: row.read_up_to as u64,
+                },
+            )
+        })
+        .collect())
+}
+
+pub async fn load_deleted_users(db: &DbPool) -> Result<Vec<Uuid>, sqlx::Error> {
+    let rows: Vec<(Uuid,)> = sqlx::query_as("SELECT user_id FROM deleted_users")
+        .fetch_all(db)
+        .await?;
+    Ok(rows.into_iter().map(|(user_id,)| user_id).collect())
+}
+
+pub async fn load_user_names(db: &DbPool) -> Result<Vec<(Uuid, String)>, sqlx::Error> {
+    sqlx::query_as("SELECT user_id, name FROM user_names")
+        .fetch_all(db)
+        .await
+}
+
+// Newest hits first, pass the returned cursor back to get the next page
+// The body is escaped before ts_headline so the only markup in a snippet is ours
+pub async fn search_messages(
+    db: &DbPool,
+    search: &SearchQuery,
+) -> Result<(Vec<SearchHit>, Option<u64>), sqlx::Error> {
+    let rows = sqlx::query_as::<_, SearchRow>(
+        "SELECT m.id, m.conversation_id, m.sender_id, m.sent_at,
+            ts_headline(
+                'simple',
+                replace(replace(replace(m.body, '&', '&amp;'), '<', '&lt;'), '>', '&gt;'),
+                query,
+                'StartSel=<mark>, StopSel=</mark>, MaxWords=20, MinWords=5, MaxFragments=2'
+            ) AS snippet
+         FROM messages m
+         JOIN conversation_participants p
+            ON p.conversation_id = m.conversation_id AND p.user_id = $1,
+         websearch_to_tsquery('simple', $2) query
+         WHERE m.search @@ query
+            AND m.deleted_at IS NULL
+            AND (m.expires_at IS NULL OR m.expires_at > now())
+            AND ($3::uuid IS NULL OR m.conversation_id = $3)
+            AND ($4::bigint IS NULL OR m.id < $4)
+         ORDER BY m.id DESC
+         LIMIT $5",
+    )
+    .bind(search.user_id)
+    .bind(&search.query)
+    .bind(search.conversation_id)
+    .bind(search.cursor.map(|c| c as i64))
+    // One extra so we know if there's another page
+    .bind(search.limit as i64 + 1)
+    .fetch_all(db)
+    .await?;
+
+    let mut hits: Vec<SearchHit> = rows
+        .into_iter()
+        .map(|row| SearchHit {
+            conversation: row.conversation_id.to_string(),
+            message_id: row.id as u64,
+            from: row.sender_id.to_string(),
+            sent_at: row.sent_at,
+            snippet: row.snippet,
+        })
+        .collect();
+    let next_cursor = if hits.len() > search.limit {
+        hits.truncate(search.limit);
+        hits.last().map(|h| h.message_id)
+    } else {
+        None
+    };
+    Ok((hits, next_cursor))
+}
@@ -0,0 +1,26 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    DoNotDisturb,
+    Offline,
+}
+
+#[derive(Clone, Copy)]
+pub struct Presence {
+    pub status: PresenceStatus,
+    pub last_seen: DateTime<Utc>,
+}
+
+impl Presence {
+    pub fn new(status: PresenceStatus) -> Presence {
+        Presence {
+            status,
+            last_seen: Utc::now(),
+        }
+    }
+}
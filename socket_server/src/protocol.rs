@@ -1,9 +1,21 @@
+use std::collections::{HashMap, HashSet};
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::{mpsc::UnboundedSender, oneshot};
 use tokio_tungstenite::tungstenite::Message;
 use uuid::Uuid;
 
+use crate::{
+    attachment::Attachment,
+    conversation::Conversation,
+    directory::UsernameLookup,
+    message::{MessageView, ReceiptStatus, ReceiptView},
+    persistence::SearchHit,
+    presence::PresenceStatus,
+    scheduler::ScheduledView,
+};
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Command {
@@ -11,21 +23,224 @@ pub enum Command {
         token: String,
     },
     CreateConversation {
-        participant: String,
+        participant: String, // User id, or a username with or without the @
     }, // Just take the other side, we'll use the second as ourself
     Say {
         message: String,
         conversation_id: String,
+        #[serde(default)]
+        reply_to: Option<u64>, // Must be a message in the same conversation
+        #[serde(default)]
+        attachments: Vec<Uuid>, // Ids from POST /attachments, the message can be empty if there are some
+    },
+    // End-to-end encrypted, one ciphertext per recipient device keyed by devices.id
+    // The server never looks inside these, it only routes them
+    SendEnvelope {
+        conversation_id: String,
+        ciphertexts: HashMap<i64, String>,
+    },
+    SetStatus {
+        status: PresenceStatus,
+    },
+    // Ephemeral, clients should repeat TypingStarted every few seconds while the user keeps typing
+    TypingStarted {
+        conversation_id: String,
+    },
+    TypingStopped {
+        conversation_id: String,
+    },
+    MarkDelivered {
+        conversation_id: String,
+        up_to_message_id: u64,
+    },
+    MarkRead {
+        conversation_id: String,
+        up_to_message_id: u64,
+    },
+    // Only the original sender may edit or delete
+    EditMessage {
+        message_id: u64,
+        message: String,
+    },
+    DeleteMessage {
+        message_id: u64,
+    },
+    // One of each emoji per user per message
+    React {
+        message_id: u64,
+        emoji: String,
+    },
+    Unreact {
+        message_id: u64,
+        emoji: String,
+    },
+    // Messages sent after this disappear ttl_seconds after they were sent, null turns it off
+    SetMessageTtl {
+        conversation_id: String,
+        ttl_seconds: Option<u64>,
+    },
+    ScheduleSay {
+        conversation_id: String,
+        message: String,
+        #[serde(with = "chrono::serde::ts_seconds")]
+        send_at: DateTime<Utc>,
+    },
+    ListScheduled {
+        #[serde(default)]
+        conversation_id: Option<String>,
+    },
+    CancelScheduled {
+        schedule_id: String,
+    },
+    GetHistory {
+        conversation_id: String,
+        #[serde(default)]
+        before: Option<u64>, // Message id to page back from, newest page if missing
+        #[serde(default)]
+        limit: Option<usize>,
+        #[serde(default)]
+        thread: Option<u64>, // Root message id, only that message and its replies are returned
+    },
+    SearchMessages {
+        query: String,
+        #[serde(default)]
+        conversation_id: Option<String>, // Every conversation you're in if missing
+        #[serde(default)]
+        limit: Option<usize>,
+        #[serde(default)]
+        cursor: Option<u64>, // next_cursor from the previous page
     },
 }
 
+impl Command {
+    // Matches the serialized tag, used to look up rate limits
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Command::Authenticate { .. } => "authenticate",
+            Command::CreateConversation { .. } => "create_conversation",
+            Command::Say { .. } => "say",
+            Command::SendEnvelope { .. } => "send_envelope",
+            Command::SetStatus { .. } => "set_status",
+            Command::TypingStarted { .. } => "typing_started",
+            Command::TypingStopped { .. } => "typing_stopped",
+            Command::MarkDelivered { .. } => "mark_delivered",
+            Command::MarkRead { .. } => "mark_read",
+            Command::EditMessage { .. } => "edit_message",
+            Command::DeleteMessage { .. } => "delete_message",
+            Command::React { .. } => "react",
+            Command::Unreact { .. } => "unreact",
+            Command::SetMessageTtl { .. } => "set_message_ttl",
+            Command::ScheduleSay { .. } => "schedule_say",
+            Command::ListScheduled { .. } => "list_scheduled",
+            Command::CancelScheduled { .. } => "cancel_scheduled",
+            Command::GetHistory { .. } => "get_history",
+            Command::SearchMessages { .. } => "search_messages",
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum ServerMsg {
-    Chat {
+    Chat(MessageView),
+    MessageEdited {
         conversation: String,
-        from: String,
+        message_id: u64,
         message: String,
+        #[serde(with = "chrono::serde::ts_seconds")]
+        edited_at: DateTime<Utc>,
+    },
+    MessageDeleted {
+        conversation: String,
+        message_id: u64,
+        #[serde(with = "chrono::serde::ts_seconds")]
+        deleted_at: DateTime<Utc>,
+    },
+    ReactionUpdated {
+        conversation: String,
+        message_id: u64,
+        emoji: String,
+        user_id: String,
+        reacted: bool, // false when the user took their reaction back
+        count: usize,
+    },
+    // Sent on top of the Chat/MessageEdited itself so clients can notify even for muted conversations
+    Mentioned {
+        conversation: String,
+        message_id: u64,
+        from: String,
+    },
+    MessagesExpired {
+        conversation: String,
+        message_ids: Vec<u64>,
+    },
+    MessageTtlChanged {
+        conversation: String,
+        changed_by: String,
+        ttl_seconds: Option<u64>,
+    },
+    Receipt {
+        conversation: String,
+        user_id: String,
+        status: ReceiptStatus,
+        up_to_message_id: u64,
+    },
+    History {
+        conversation: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        thread: Option<u64>,
+        messages: Vec<MessageView>,
+        receipts: Vec<ReceiptView>,
+        unread: usize,
+    },
+    Envelope {
+        conversation: String,
+        from: String,
+        from_device: i64,
+        ciphertext: String,
+    },
+    PresenceChanged {
+        user_id: String,
+        status: PresenceStatus,
+        #[serde(with = "chrono::serde::ts_seconds")]
+        last_seen: DateTime<Utc>,
+    },
+    TypingStarted {
+        conversation: String,
+        user_id: String,
+    },
+    TypingStopped {
+        conversation: String,
+        user_id: String,
+    },
+    Scheduled(ScheduledView),
+    ScheduledList {
+        scheduled: Vec<ScheduledView>,
+    },
+    ScheduledCancelled {
+        id: String,
+    },
+    // Sent to the sender when a scheduled message couldn't go out when it was due
+    ScheduledFailed {
+        id: String,
+        conversation: String,
+        reason: String,
+    },
+    SearchResults {
+        query: String,
+        hits: Vec<SearchHit>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next_cursor: Option<u64>,
+    },
+    RateLimited {
+        command: String,
+        retry_after_ms: u64,
+    },
+    // Their messages now come from replaced_by too
+    UserDeleted {
+        conversation: String,
+        user_id: String,
+        replaced_by: String,
     },
     Info {
         message: String,
@@ -47,6 +262,55 @@ pub enum Event {
     Disconnected {
         client_id: u64,
     },
+    Tick,
+    Sweep,
+    ScheduledSay {
+        schedule_id: Uuid,
+    },
+    AttachmentUploaded {
+        attachment: Attachment,
+    },
+    // None if not every stored message could be loaded, then nothing is safe to delete
+    ReferencedBlobs {
+        reply: oneshot::Sender<Option<HashSet<String>>>,
+    },
+    AuthorizeAttachment {
+        user_id: Uuid,
+        attachment_id: Uuid,
+        reply: oneshot::Sender<Option<Attachment>>,
+    },
+    RevokeSessions {
+        user_id: Uuid,
+        session_version: i32, // Oldest version that is still allowed
+    },
+    UserDeleted {
+        user_id: Uuid,
+        reply: oneshot::Sender<()>, // Fires once the deletion is stored
+    },
+    AuthorizeSession {
+        user_id: Uuid,
+        session_version: i32,
+        reply: oneshot::Sender<bool>,
+    },
+    UsernameResolved {
+        client_id: u64, // Who asked, in a CreateConversation
+        username: String,
+        result: UsernameLookup,
+    },
+    ProfileUpdated {
+        user_id: Uuid,
+        name: String,
+    },
+    NameResolved {
+        user_id: Uuid,
+        name: Option<String>, // None if auth_service doesn't know them or couldn't be reached
+    },
+    ExportConversation {
+        user_id: Uuid,
+        conversation_id: Uuid,
+        // Along with what everyone in it is called, for the text export
+        reply: oneshot::Sender<Option<(Conversation, HashMap<Uuid, String>)>>,
+    },
 }
 
 // TODO: Move to models, or it's own folder idk yet
@@ -54,6 +318,8 @@ pub enum Event {
 pub struct UserInfo {
     pub id: Uuid,
     pub username: String,
+    #[serde(default)]
+    pub display_name: Option<String>,
 
     #[serde(with = "chrono::serde::ts_seconds")]
     pub created_at: DateTime<Utc>,
@@ -66,6 +332,10 @@ pub struct UserInfo {
 pub struct Claims {
     pub sub: String,
     pub user: UserInfo,
+    #[serde(default)]
+    pub device_id: Option<i64>, // devices.id from auth_service, if the user logged in with one
+    #[serde(default)]
+    pub sv: i32, // Session version, tokens from before a password change are revoked
     pub exp: usize,
     pub iat: usize,
 }
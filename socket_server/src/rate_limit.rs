@@ -0,0 +1,341 @@
+use std::{collections::HashMap, env, time::Duration};
+
+use tokio::time::Instant;
+use uuid::Uuid;
+
+// Getting limited this many times within STRIKE_WINDOW means the client isn't backing off
+const MAX_STRIKES: u32 = 50;
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy)]
+pub struct RateLimit {
+    pub burst: f64,
+    pub per_minute: f64,
+}
+
+impl RateLimit {
+    const fn new(burst: f64, per_minute: f64) -> RateLimit {
+        RateLimit { burst, per_minute }
+    }
+
+    // "burst/per_minute", e.g. 10/60
+    fn parse(value: &str) -> Option<RateLimit> {
+        let (burst, per_minute) = value.split_once('/')?;
+        let burst: f64 = burst.trim().parse().ok()?;
+        let per_minute: f64 = per_minute.trim().parse().ok()?;
+        (burst >= 1.0 && per_minute > 0.0).then_some(RateLimit { burst, per_minute })
+    }
+}
+
+// Per connection, then per user across all their devices
+#[derive(Clone, Copy)]
+pub struct CommandLimit {
+    pub connection: RateLimit,
+    pub user: RateLimit,
+}
+
+const fn limit(burst: f64, per_minute: f64) -> CommandLimit {
+    CommandLimit {
+        connection: RateLimit::new(burst, per_minute),
+        user: RateLimit::new(burst * 2.0, per_minute * 2.0),
+    }
+}
+
+const FALLBACK_LIMIT: CommandLimit = limit(20.0, 120.0);
+
+// Anything creating things for other people is the tightest, chatty ephemeral stuff the loosest
+const DEFAULT_LIMITS: &[(&str, CommandLimit)] = &[
+    ("authenticate", limit(5.0, 10.0)),
+    ("create_conversation", limit(5.0, 10.0)),
+    ("say", limit(10.0, 60.0)),
+    ("send_envelope", limit(10.0, 60.0)),
+    ("schedule_say", limit(5.0, 20.0)),
+    ("edit_message", limit(10.0, 30.0)),
+    ("delete_message", limit(10.0, 30.0)),
+    ("react", limit(20.0, 60.0)),
+    ("unreact", limit(20.0, 60.0)),
+    ("set_status", limit(5.0, 20.0)),
+    ("set_message_ttl", limit(5.0, 10.0)),
+    ("typing_started", limit(30.0, 120.0)),
+    ("typing_stopped", limit(30.0, 120.0)),
+    ("mark_delivered", limit(30.0, 240.0)),
+    ("mark_read", limit(30.0, 240.0)),
+    ("get_history", limit(20.0, 120.0)),
+    ("search_messages", limit(5.0, 30.0)),
+    ("list_scheduled", limit(10.0, 60.0)),
+    ("cancel_scheduled", limit(10.0, 60.0)),
+];
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: limit.burst,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.per_minute / 60.0).min(limit.burst);
+        self.last_refill = now;
+    }
+
+    // How long until there's a whole token, zero if there already is
+    fn wait(&self, limit: RateLimit) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - self.tokens) * 60.0 / limit.per_minute)
+    }
+}
+
+struct Strikes {
+    count: u32,
+    since: Instant,
+}
+
+pub struct RateLimiter {
+    limits: HashMap<&'static str, CommandLimit>,
+    connection_buckets: HashMap<(u64, &'static str), TokenBucket>,
+    user_buckets: HashMap<(Uuid, &'static str), TokenBucket>,
+    strikes: HashMap<u64, Strikes>,
+}
+
+impl RateLimiter {
+    // RATE_LIMIT_SAY=10/60 overrides the per connection limit for Say, RATE_LIMIT_SAY_USER the per user one
+    pub fn from_env() -> RateLimiter {
+        let mut limits = HashMap::new();
+        for (kind, default) in DEFAULT_LIMITS {
+            let var = format!("RATE_LIMIT_{}", kind.to_uppercase());
+            let mut limit = *default;
+            if let Some(l) = env::var(&var).ok().and_then(|v| RateLimit::parse(&v)) {
+                limit.connection = l;
+            }
+            if let Some(l) = env::var(format!("{var}_USER"))
+                .ok()
+                .and_then(|v| RateLimit::parse(&v))
+            {
+                limit.user = l;
+            }
+            limits.insert(*kind, limit);
+        }
+        RateLimiter {
+            limits,
+            connection_buckets: HashMap::new(),
+            user_buckets: HashMap::new(),
+            strikes: HashMap::new(),
+        }
+    }
+
+    // Takes a token from both buckets, or neither and says how long to wait
+    pub fn check(
+        &mut self,
+        connection_id: u64,
+        user_id: Option<Uuid>,
+        kind: &'static str,
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let limit = self.limits.get(kind).copied().unwrap_or(FALLBACK_LIMIT);
+
+        let connection_bucket = self
+            .connection_buckets
+            .entry((connection_id, kind))
+            .or_insert_with(|| TokenBucket::new(limit.connection, now));
+        connection_bucket.refill(limit.connection, now);
+        let mut wait = connection_bucket.wait(limit.connection);
+
+        // Not authenticated yet means there's no user to count against
+        let user_bucket = user_id.map(|user_id| {
+            let bucket = self
+                .user_buckets
+                .entry((user_id, kind))
+                .or_insert_with(|| TokenBucket::new(limit.user, now));
+            bucket.refill(limit.user, now);
+            wait = wait.max(bucket.wait(limit.user));
+            bucket
+        });
+
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+        if let Some(bucket) = user_bucket {
+            bucket.tokens -= 1.0;
+        }
+        if let Some(bucket) = self.connection_buckets.get_mut(&(connection_id, kind)) {
+            bucket.tokens -= 1.0;
+        }
+        Ok(())
+    }
+
+    // Returns true once the connection has been limited too often to still be an accident
+    pub fn strike(&mut self, connection_id: u64, now: Instant) -> bool {
+        let strikes = self.strikes.entry(connection_id).or_insert(Strikes {
+            count: 0,
+            since: now,
+        });
+        if now.duration_since(strikes.since) > STRIKE_WINDOW {
+            strikes.count = 0;
+            strikes.since = now;
+        }
+        strikes.count += 1;
+        strikes.count >= MAX_STRIKES
+    }
+
+    pub fn forget_connection(&mut self, connection_id: u64) {
+        self.connection_buckets
+            .retain(|(id, _), _| *id != connection_id);
+        self.strikes.remove(&connection_id);
+    }
+
+    // A bucket that has refilled is the same as no bucket, so there's no point keeping it
+    pub fn prune(&mut self, now: Instant) {
+        let limits = &self.limits;
+        let is_full = |kind: &'static str, bucket: &mut TokenBucket, user: bool| {
+            let limit = limits.get(kind).copied().unwrap_or(FALLBACK_LIMIT);
+            let limit = if user { limit.user } else { limit.connection };
+            bucket.refill(limit, now);
+            bucket.tokens >= limit.burst
+        };
+        self.user_buckets
+            .retain(|(_, kind), bucket| !is_full(kind, bucket, true));
+        self.connection_buckets
+            .retain(|(_, kind), bucket| !is_full(kind, bucket, false));
+        self.strikes
+            .retain(|_, strikes| now.duration_since(strikes.since) <= STRIKE_WINDOW);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USER: Uuid = Uuid::from_u128(1);
+
+    // Not from_env, so RATE_LIMIT_* in the environment can't change what's being tested
+    fn limiter(limit: CommandLimit) -> RateLimiter {
+        RateLimiter {
+            limits: HashMap::from([("say", limit)]),
+            connection_buckets: HashMap::new(),
+            user_buckets: HashMap::new(),
+            strikes: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn allows_a_burst_then_says_how_long_to_wait() {
+        let mut limiter = limiter(limit(3.0, 60.0));
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check(1, Some(USER), "say", now).is_ok());
+        }
+        assert_eq!(
+            limiter.check(1, Some(USER), "say", now),
+            Err(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn refills_over_time_but_not_past_the_burst() {
+        let mut limiter = limiter(limit(2.0, 60.0));
+        let now = Instant::now();
+        for _ in 0..2 {
+            assert!(limiter.check(1, None, "say", now).is_ok());
+        }
+        assert!(limiter.check(1, None, "say", now).is_err());
+
+        let later = now + Duration::from_secs(1);
+        assert!(limiter.check(1, None, "say", later).is_ok());
+        assert!(limiter.check(1, None, "say", later).is_err());
+
+        // An hour idle is still only a burst's worth
+        let much_later = later + Duration::from_secs(3600);
+        for _ in 0..2 {
+            assert!(limiter.check(1, None, "say", much_later).is_ok());
+        }
+        assert!(limiter.check(1, None, "say", much_later).is_err());
+    }
+
+    #[test]
+    fn user_bucket_is_shared_between_devices() {
+        let mut limiter = limiter(CommandLimit {
+            connection: RateLimit::new(2.0, 60.0),
+            user: RateLimit::new(3.0, 60.0),
+        });
+        let now = Instant::now();
+        for _ in 0..2 {
+            assert!(limiter.check(1, Some(USER), "say", now).is_ok());
+        }
+        assert!(limiter.check(2, Some(USER), "say", now).is_ok());
+        // Connection 2 still has a token, the user doesn't
+        assert!(limiter.check(2, Some(USER), "say", now).is_err());
+        // Someone else isn't affected
+        assert!(
+            limiter
+                .check(3, Some(Uuid::from_u128(2)), "say", now)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn a_refused_check_takes_from_neither_bucket() {
+        let mut limiter = limiter(CommandLimit {
+            connection: RateLimit::new(1.0, 60.0),
+            user: RateLimit::new(2.0, 60.0),
+        });
+        let now = Instant::now();
+        assert!(limiter.check(1, Some(USER), "say", now).is_ok());
+        for _ in 0..5 {
+            assert!(limiter.check(1, Some(USER), "say", now).is_err());
+        }
+        // The user bucket wasn't drained by connection 1 being refused
+        assert!(limiter.check(2, Some(USER), "say", now).is_ok());
+    }
+
+    #[test]
+    fn unknown_commands_get_the_fallback_limit() {
+        let mut limiter = limiter(limit(1.0, 60.0));
+        let now = Instant::now();
+        for _ in 0..FALLBACK_LIMIT.connection.burst as usize {
+            assert!(limiter.check(1, None, "something_else", now).is_ok());
+        }
+        assert!(limiter.check(1, None, "something_else", now).is_err());
+    }
+
+    #[test]
+    fn strikes_add_up_within_the_window() {
+        let mut limiter = limiter(FALLBACK_LIMIT);
+        let now = Instant::now();
+        for _ in 0..MAX_STRIKES - 1 {
+            assert!(!limiter.strike(1, now));
+        }
+        assert!(limiter.strike(1, now));
+        // Other connections have their own count
+        assert!(!limiter.strike(2, now));
+    }
+
+    #[test]
+    fn strikes_reset_after_the_window() {
+        let mut limiter = limiter(FALLBACK_LIMIT);
+        let now = Instant::now();
+        for _ in 0..MAX_STRIKES - 1 {
+            assert!(!limiter.strike(1, now));
+        }
+        let later = now + STRIKE_WINDOW + Duration::from_secs(1);
+        assert!(!limiter.strike(1, later));
+    }
+
+    #[test]
+    fn parses_burst_and_rate() {
+        let parsed = RateLimit::parse(" 10 / 60 ").unwrap();
+        assert_eq!((parsed.burst, parsed.per_minute), (10.0, 60.0));
+        assert!(RateLimit::parse("0/60").is_none());
+        assert!(RateLimit::parse("10/0").is_none());
+        assert!(RateLimit::parse("10").is_none());
+        assert!(RateLimit::parse("ten/60").is_none());
+    }
+}
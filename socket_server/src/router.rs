@@ -1,26 +1,131 @@
-use std::env;
+use std::{env, path::PathBuf, time::Duration};
 
 use crate::{
+    attachment::remove_unreferenced_blobs,
+    db::DbPool,
+    directory::AuthDirectory,
     handlers::{
-        connected::handle_connected_event, disconnected::handle_disconnected_event,
+        attachment::{
+            handle_attachment_uploaded_event, handle_authorize_attachment_event,
+            handle_referenced_blobs_event,
+        },
+        connected::handle_connected_event,
+        directory::{
+            handle_name_resolved_event, handle_profile_updated_event,
+            handle_username_resolved_event,
+        },
+        disconnected::handle_disconnected_event,
+        export::handle_export_conversation_event,
         received::handle_received_event,
+        scheduled::handle_scheduled_say_event,
+        sessions::{
+            handle_authorize_session_event, handle_revoke_sessions_event, handle_user_deleted_event,
+        },
+        sweep::handle_sweep_event,
+        tick::handle_tick_event,
+    },
+    persistence::{
+        Store, load_conversations, load_deleted_users, load_last_message_id, load_messages,
+        load_receipts, load_scheduled, load_user_names,
     },
     protocol::Event,
+    rate_limit::RateLimiter,
+    scheduler::ScheduleOp,
     state::RouterState,
+    text::DEFAULT_MAX_MESSAGE_GRAPHEMES,
 };
 
 use futures_util::{SinkExt, StreamExt, stream::SplitSink};
 use jsonwebtoken::DecodingKey;
 use tokio::{
     net::TcpStream,
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
 };
 use tokio_tungstenite::{WebSocketStream, tungstenite::Message};
 
-pub async fn handle_router(mut received: UnboundedReceiver<Event>) {
+pub async fn handle_router(
+    mut received: UnboundedReceiver<Event>,
+    db: Option<DbPool>,
+    scheduler: UnboundedSender<ScheduleOp>,
+    auth_directory: AuthDirectory,
+) {
     let decoding_key =
         DecodingKey::from_secret(env::var("JWT_SECRET").expect("no JWT_SECRET set").as_ref());
-    let mut router_state: RouterState = RouterState::new(decoding_key);
+    let max_message_graphemes = env::var("MAX_MESSAGE_GRAPHEMES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_MESSAGE_GRAPHEMES);
+    let store = Store::new(db.clone());
+    let mut router_state: RouterState = RouterState::new(
+        decoding_key,
+        max_message_graphemes,
+        store,
+        scheduler,
+        RateLimiter::from_env(),
+        auth_directory,
+    );
+
+    // Pick up where we left off before taking any events
+    if let Some(db) = &db {
+        match load_conversations(db).await {
+            Ok(conversations) => {
+                for conversation in conversations {
+                    router_state
+                        .conversations
+                        .insert(conversation.id, conversation);
+                }
+            }
+            Err(e) => eprintln!("failed to load conversations: {e}"),
+        }
+        match load_messages(db).await {
+            Ok(messages) => {
+                for message in messages {
+                    router_state.restore_message(message);
+                }
+            }
+            Err(e) => {
+                eprintln!("failed to load messages: {e}");
+                router_state.blobs_loaded = false;
+            }
+        }
+        // Not just the loaded messages, expired ones had their rows deleted but their ids stay used
+        match load_last_message_id(db).await {
+            Ok(last_id) => {
+                router_state.next_message_id = router_state.next_message_id.max(last_id + 1);
+            }
+            Err(e) => eprintln!("failed to load last message id: {e}"),
+        }
+        match load_receipts(db).await {
+            Ok(receipts) => {
+                for (conversation_id, user_id, receipt) in receipts {
+                    if let Some(conversation) = router_state.conversations.get_mut(&conversation_id)
+                    {
+                        conversation.receipts.insert(user_id, receipt);
+                    }
+                }
+            }
+            Err(e) => eprintln!("failed to load receipts: {e}"),
+        }
+        match load_scheduled(db).await {
+            Ok(scheduled) => {
+                for s in scheduled {
+                    router_state.restore_scheduled(s);
+                }
+            }
+            Err(e) => eprintln!("failed to load scheduled messages: {e}"),
+        }
+        match load_deleted_users(db).await {
+            Ok(user_ids) => router_state.deleted_users.extend(user_ids),
+            Err(e) => eprintln!("failed to load deleted users: {e}"),
+        }
+        match load_user_names(db).await {
+            Ok(names) => router_state.names.extend(names),
+            Err(e) => eprintln!("failed to load user names: {e}"),
+        }
+    }
 
     while let Some(ev) = received.recv().await {
         match ev {
@@ -33,6 +138,102 @@ pub async fn handle_router(mut received: UnboundedReceiver<Event>) {
             Event::Disconnected { client_id } => {
                 handle_disconnected_event(&mut router_state, client_id);
             }
+            Event::Tick => {
+                handle_tick_event(&mut router_state);
+            }
+            Event::Sweep => {
+                handle_sweep_event(&mut router_state);
+            }
+            Event::ScheduledSay { schedule_id } => {
+                handle_scheduled_say_event(&mut router_state, schedule_id);
+            }
+            Event::AttachmentUploaded { attachment } => {
+                handle_attachment_uploaded_event(&mut router_state, attachment);
+            }
+            Event::ReferencedBlobs { reply } => {
+                handle_referenced_blobs_event(&mut router_state, reply);
+            }
+            Event::AuthorizeAttachment {
+                user_id,
+                attachment_id,
+                reply,
+            } => {
+                handle_authorize_attachment_event(&mut router_state, user_id, attachment_id, reply);
+            }
+            Event::RevokeSessions {
+                user_id,
+                session_version,
+            } => {
+                handle_revoke_sessions_event(&mut router_state, user_id, session_version);
+            }
+            Event::UserDeleted { user_id, reply } => {
+                handle_user_deleted_event(&mut router_state, user_id, reply);
+            }
+            Event::AuthorizeSession {
+                user_id,
+                session_version,
+                reply,
+            } => {
+                handle_authorize_session_event(&mut router_state, user_id, session_version, reply);
+            }
+            Event::UsernameResolved {
+                client_id,
+                username,
+                result,
+            } => {
+                handle_username_resolved_event(&mut router_state, client_id, username, result);
+            }
+            Event::ProfileUpdated { user_id, name } => {
+                handle_profile_updated_event(&mut router_state, user_id, name);
+            }
+            Event::NameResolved { user_id, name } => {
+                handle_name_resolved_event(&mut router_state, user_id, name);
+            }
+            Event::ExportConversation {
+                user_id,
+                conversation_id,
+                reply,
+            } => {
+                handle_export_conversation_event(&router_state, user_id, conversation_id, reply);
+            }
+        }
+    }
+}
+
+// Wakes the router up regularly so it can expire things without waiting for a client to do something
+pub async fn handle_ticker(tx: UnboundedSender<Event>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        interval.tick().await;
+        if tx.send(Event::Tick).is_err() {
+            break;
+        }
+    }
+}
+
+// Disappearing messages don't need to go on the second, so this runs a lot less often than the ticker
+pub async fn handle_sweeper(tx: UnboundedSender<Event>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+        if tx.send(Event::Sweep).is_err() {
+            break;
+        }
+    }
+}
+
+// Attachment bytes outlive their messages unless something deletes them, this is that something
+// Runs once at startup too, for uploads that were never sent before the last restart
+pub async fn handle_blob_sweeper(tx: UnboundedSender<Event>, dir: PathBuf) {
+    let mut interval = tokio::time::interval(Duration::from_secs(10 * 60));
+    loop {
+        interval.tick().await;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if tx.send(Event::ReferencedBlobs { reply: reply_tx }).is_err() {
+            break;
+        }
+        if let Ok(Some(referenced)) = reply_rx.await {
+            remove_unreferenced_blobs(&dir, &referenced).await;
         }
     }
 }
@@ -56,10 +257,17 @@ pub async fn handle_connection(tx: UnboundedSender<Event>, stream: TcpStream, cl
     let (writer, mut reader) = ws_stream.split();
 
     // Handle writer thread
-    tokio::spawn(handle_client_writer(writer, out_rx));
+    let mut writer_task = tokio::spawn(handle_client_writer(writer, out_rx));
 
-    // Handle reader
-    while let Some(item) = reader.next().await {
+    // Handle reader, until the client goes away or the router hangs up on them
+    loop {
+        let item = tokio::select! {
+            item = reader.next() => item,
+            _ = &mut writer_task => break,
+        };
+        let Some(item) = item else {
+            break;
+        };
         match item {
             Ok(Message::Text(t)) => {
                 // Just forward commands to the router
@@ -0,0 +1,88 @@
+use std::collections::{BTreeSet, HashMap};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use uuid::Uuid;
+
+use crate::protocol::Event;
+
+pub const MAX_SCHEDULED_PER_USER: usize = 100;
+
+#[derive(Clone)]
+pub struct ScheduledMessage {
+    pub id: Uuid,
+    pub conversation_id: Uuid,
+    pub sender_id: Uuid,
+    pub message: String, // Already normalized when it was scheduled
+    pub send_at: DateTime<Utc>,
+}
+
+impl ScheduledMessage {
+    pub fn view(&self) -> ScheduledView {
+        ScheduledView {
+            id: self.id.to_string(),
+            conversation: self.conversation_id.to_string(),
+            message: self.message.clone(),
+            send_at: self.send_at,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ScheduledView {
+    pub id: String,
+    pub conversation: String,
+    pub message: String,
+    #[serde(with = "chrono::serde::ts_seconds")]
+    pub send_at: DateTime<Utc>,
+}
+
+pub enum ScheduleOp {
+    Add { id: Uuid, send_at: DateTime<Utc> },
+    Cancel { id: Uuid },
+}
+
+// Only knows when things are due, the router keeps the messages themselves
+// When one comes up it goes back to the router as an event, same as anything a client sends
+pub async fn handle_scheduler(tx: UnboundedSender<Event>, mut ops: UnboundedReceiver<ScheduleOp>) {
+    let mut queue: BTreeSet<(DateTime<Utc>, Uuid)> = BTreeSet::new();
+    let mut due_at: HashMap<Uuid, DateTime<Utc>> = HashMap::new();
+
+    loop {
+        let next = queue.first().copied();
+        let sleep_for = next
+            .map(|(send_at, _)| (send_at - Utc::now()).to_std().unwrap_or_default())
+            .unwrap_or_default();
+
+        tokio::select! {
+            op = ops.recv() => match op {
+                Some(ScheduleOp::Add { id, send_at }) => {
+                    if let Some(previous) = due_at.insert(id, send_at) {
+                        queue.remove(&(previous, id));
+                    }
+                    queue.insert((send_at, id));
+                }
+                Some(ScheduleOp::Cancel { id }) => {
+                    if let Some(previous) = due_at.remove(&id) {
+                        queue.remove(&(previous, id));
+                    }
+                }
+                None => break,
+            },
+            _ = tokio::time::sleep(sleep_for), if next.is_some() => {
+                let now = Utc::now();
+                while let Some((send_at, id)) = queue.first().copied() {
+                    if send_at > now {
+                        break;
+                    }
+                    queue.pop_first();
+                    due_at.remove(&id);
+                    if tx.send(Event::ScheduledSay { schedule_id: id }).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
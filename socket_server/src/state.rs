@@ -1,44 +1,341 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
 
+use chrono::{DateTime, Utc};
 use jsonwebtoken::DecodingKey;
-use tokio::sync::mpsc::UnboundedSender;
-use tokio_tungstenite::tungstenite::Message;
+use tokio::{sync::mpsc::UnboundedSender, time::Instant};
+use tokio_tungstenite::tungstenite::{
+    Message,
+    protocol::{CloseFrame, frame::coding::CloseCode},
+};
 use uuid::Uuid;
 
 use crate::{
-    conversation::Conversation,
+    attachment::Attachment,
+    conversation::{Conversation, DELETED_USER_ID, DELETED_USER_NAME},
+    directory::AuthDirectory,
+    mentions::{Mention, parse_mentions},
+    message::{ChatMessage, ReceiptStatus},
+    persistence::{Store, Write},
+    presence::{Presence, PresenceStatus},
     protocol::ServerMsg,
+    rate_limit::RateLimiter,
+    scheduler::{ScheduleOp, ScheduledMessage},
     send::{SendServerMsgError, send_server_msg},
 };
 
+// If a client stops refreshing TypingStarted (crashed, lost network...) we stop it for them
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
 pub struct RouterState {
     pub decoding_key: DecodingKey,
     pub connections: HashMap<u64, UnboundedSender<Message>>,
     pub connection_to_user: HashMap<u64, Uuid>,
+    pub connection_to_device: HashMap<u64, i64>,
+    // user -> device -> connections, None holds connections from tokens without a device
+    pub user_sessions: HashMap<Uuid, HashMap<Option<i64>, HashSet<u64>>>,
+    pub presence: HashMap<Uuid, Presence>,
+    // (conversation, user) -> when the indicator expires, only ever kept in memory
+    pub typing: HashMap<(Uuid, Uuid), Instant>,
     //pub users: HashMap<Uuid, UserInfo>,
     pub conversations: HashMap<Uuid, Conversation>,
+    pub next_message_id: u64,
+    pub message_to_conversation: HashMap<u64, Uuid>,
+    pub max_message_graphemes: usize,
+    // lowercased username -> id, filled in as people authenticate or get looked up
+    pub directory: HashMap<String, Uuid>,
+    pub auth_directory: AuthDirectory,
+    // user -> what messages call them, from their token, a lookup or a profile update, kept in user_names
+    pub names: HashMap<Uuid, String>,
+    pub attachments: HashMap<Uuid, Attachment>,
+    // False if stored messages failed to load, their attachments' blobs would look unreferenced
+    pub blobs_loaded: bool,
+    pub store: Store,
+    pub scheduler: UnboundedSender<ScheduleOp>,
+    pub scheduled: HashMap<Uuid, ScheduledMessage>,
+    pub rate_limiter: RateLimiter,
+    // user -> lowest session version still allowed, only set once auth_service has revoked something
+    pub session_versions: HashMap<Uuid, i32>,
+    pub connection_session_version: HashMap<u64, i32>,
+    // Their unexpired tokens are refused, auth_service won't hand out new ones
+    pub deleted_users: HashSet<Uuid>,
+    // Being looked up in auth_service by id, so a page full of their messages only asks once
+    pub pending_names: HashSet<Uuid>,
 }
 
 impl RouterState {
-    pub fn new(decoding_key: DecodingKey) -> RouterState {
+    pub fn new(
+        decoding_key: DecodingKey,
+        max_message_graphemes: usize,
+        store: Store,
+        scheduler: UnboundedSender<ScheduleOp>,
+        rate_limiter: RateLimiter,
+        auth_directory: AuthDirectory,
+    ) -> RouterState {
         RouterState {
             decoding_key,
             connections: HashMap::new(),
             connection_to_user: HashMap::new(),
+            connection_to_device: HashMap::new(),
+            user_sessions: HashMap::new(),
+            presence: HashMap::new(),
+            typing: HashMap::new(),
             //users: HashMap::new(),
             conversations: HashMap::new(),
+            next_message_id: 1,
+            message_to_conversation: HashMap::new(),
+            max_message_graphemes,
+            directory: HashMap::new(),
+            auth_directory,
+            names: HashMap::from([(DELETED_USER_ID, DELETED_USER_NAME.to_string())]),
+            attachments: HashMap::new(),
+            blobs_loaded: true,
+            store,
+            scheduler,
+            scheduled: HashMap::new(),
+            rate_limiter,
+            session_versions: HashMap::new(),
+            connection_session_version: HashMap::new(),
+            deleted_users: HashSet::new(),
+            pending_names: HashSet::new(),
+        }
+    }
+
+    pub fn register_session(&mut self, client_id: u64, user_id: Uuid, device_id: Option<i64>) {
+        // Only the first connection brings the user online, extra devices keep whatever status they set
+        let first_connection = !self.user_sessions.contains_key(&user_id);
+
+        self.connection_to_user.insert(client_id, user_id);
+        if let Some(device_id) = device_id {
+            self.connection_to_device.insert(client_id, device_id);
+        }
+        self.user_sessions
+            .entry(user_id)
+            .or_default()
+            .entry(device_id)
+            .or_default()
+            .insert(client_id);
+
+        if first_connection {
+            self.set_presence(user_id, PresenceStatus::Online);
+        }
+    }
+
+    pub fn connections_for_user(&self, user_id: &Uuid) -> Vec<u64> {
+        match self.user_sessions.get(user_id) {
+            Some(devices) => devices.values().flatten().copied().collect(),
+            None => vec![],
         }
     }
 
     pub fn disconnect_client(&mut self, client_id: u64) {
         self.connections.remove(&client_id);
-        self.connection_to_user.remove(&client_id);
+        self.rate_limiter.forget_connection(client_id);
+        self.connection_session_version.remove(&client_id);
+        let device_id = self.connection_to_device.remove(&client_id);
+        let Some(user_id) = self.connection_to_user.remove(&client_id) else {
+            return;
+        };
+        let Some(devices) = self.user_sessions.get_mut(&user_id) else {
+            return;
+        };
+        if let Some(device_connections) = devices.get_mut(&device_id) {
+            device_connections.remove(&client_id);
+            if device_connections.is_empty() {
+                devices.remove(&device_id);
+            }
+        }
+
+        // The user is only gone once their last device is, so closing a laptop while
+        // the phone is still connected doesn't flicker them offline for everyone
+        if devices.is_empty() {
+            self.user_sessions.remove(&user_id);
+            self.stop_all_typing(user_id);
+            self.set_presence(user_id, PresenceStatus::Offline);
+        }
 
         // TODO: Consider
         // We may want to also drop the users info if they have no conversations, and potentially the conversations, but I don't want to do that yet.
-        // Here's where things get tricky, what if the user is connected on two devices? What happens?
-        // If we try to send a disconnect message to their conversations, what happens if they disconnect from one device but not the second?
-        // We probably don't want to send a disconnect message if that happens...
+    }
+
+    // Sends a close frame and forgets about the connection, dropping our end closes the socket once the frame is out
+    pub fn close_connection(&mut self, client_id: u64, code: CloseCode, reason: &str) {
+        if let Some(tx) = self.connections.get(&client_id) {
+            let _ = tx.send(Message::Close(Some(CloseFrame {
+                code,
+                reason: reason.to_string().into(),
+            })));
+        }
+        self.disconnect_client(client_id);
+    }
+
+    pub fn revoke_sessions(&mut self, user_id: Uuid, session_version: i32) {
+        let minimum = self
+            .session_versions
+            .entry(user_id)
+            .or_insert(session_version);
+        *minimum = (*minimum).max(session_version);
+        let minimum = *minimum;
+
+        let stale: Vec<u64> = self
+            .connections_for_user(&user_id)
+            .into_iter()
+            .filter(|c| {
+                self.connection_session_version
+                    .get(c)
+                    .is_some_and(|v| *v < minimum)
+            })
+            .collect();
+        for client_id in stale {
+            self.close_connection(client_id, CloseCode::Policy, "Session revoked");
+        }
+    }
+
+    // The account is gone from auth_service, so it's gone from every conversation here too
+    pub fn delete_user(&mut self, user_id: Uuid) {
+        // auth_service retries until it hears back, the second time round there's nothing left to do
+        if !self.deleted_users.insert(user_id) {
+            return;
+        }
+        for client_id in self.connections_for_user(&user_id) {
+            self.close_connection(client_id, CloseCode::Policy, "Account deleted");
+        }
+        self.presence.remove(&user_id);
+        self.names.remove(&user_id);
+        self.store.write(Write::DeleteUserName { user_id });
+        self.directory.retain(|_, id| *id != user_id);
+
+        let scheduled: Vec<Uuid> = self
+            .scheduled
+            .values()
+            .filter(|s| s.sender_id == user_id)
+            .map(|s| s.id)
+            .collect();
+        for schedule_id in scheduled {
+            self.cancel_scheduled(schedule_id);
+        }
+
+        let mut changed: Vec<Uuid> = vec![];
+        for conversation in self.conversations.values_mut() {
+            if !conversation.replace_participant_with_placeholder(user_id) {
+                continue;
+            }
+            for message in &mut conversation.messages {
+                let mut touched = message.from == user_id;
+                if touched {
+                    message.from = DELETED_USER_ID;
+                }
+                for users in message.reactions.values_mut() {
+                    touched |= users.remove(&user_id);
+                }
+                message.reactions.retain(|_, users| !users.is_empty());
+                if touched {
+                    self.store.save_message(message);
+                }
+            }
+            self.store.save_conversation(conversation);
+            changed.push(conversation.id);
+        }
+        self.store.write(Write::DeleteReceipts { user_id });
+
+        for conversation_id in changed {
+            self.send_server_msg_to_conversation(
+                conversation_id,
+                &ServerMsg::UserDeleted {
+                    conversation: conversation_id.to_string(),
+                    user_id: user_id.to_string(),
+                    replaced_by: DELETED_USER_ID.to_string(),
+                },
+            );
+        }
+    }
+
+    pub fn set_name(&mut self, user_id: Uuid, name: String) {
+        if self.names.get(&user_id) == Some(&name) {
+            return;
+        }
+        self.store.write(Write::SaveUserName {
+            user_id,
+            name: name.clone(),
+        });
+        self.names.insert(user_id, name);
+    }
+
+    // Asks auth_service about senders we have no name for, Event::NameResolved fills them in
+    pub fn resolve_names(&mut self, user_ids: impl IntoIterator<Item = Uuid>) {
+        for user_id in user_ids {
+            if self.names.contains_key(&user_id)
+                || self.is_deleted_user(&user_id)
+                || !self.pending_names.insert(user_id)
+            {
+                continue;
+            }
+            if !self.auth_directory.resolve_name(user_id) {
+                self.pending_names.remove(&user_id);
+            }
+        }
+    }
+
+    pub fn is_deleted_user(&self, user_id: &Uuid) -> bool {
+        *user_id == DELETED_USER_ID || self.deleted_users.contains(user_id)
+    }
+
+    // Still signed and unexpired, but from before a password change or the account is gone
+    pub fn is_session_revoked(&self, user_id: &Uuid, session_version: i32) -> bool {
+        self.is_deleted_user(user_id)
+            || self
+                .session_versions
+                .get(user_id)
+                .is_some_and(|minimum| session_version < *minimum)
+    }
+
+    pub fn set_presence(&mut self, user_id: Uuid, status: PresenceStatus) {
+        let presence = Presence::new(status);
+        self.presence.insert(user_id, presence);
+        self.send_presence_to_contacts(user_id, presence);
+    }
+
+    // Everyone who shares at least one conversation with this user, including the user's other devices
+    pub fn contacts_of(&self, user_id: &Uuid) -> HashSet<Uuid> {
+        let mut contacts: HashSet<Uuid> = HashSet::new();
+        for conversation in self.conversations.values() {
+            if conversation.participants.contains(user_id) {
+                contacts.extend(conversation.participants.iter().copied());
+            }
+        }
+        contacts
+    }
+
+    fn send_presence_to_contacts(&mut self, user_id: Uuid, presence: Presence) {
+        let mut senders: Vec<(u64, UnboundedSender<Message>)> = vec![];
+        for contact in self.contacts_of(&user_id) {
+            for connection_id in self.connections_for_user(&contact) {
+                if let Some(sender) = self.connections.get(&connection_id) {
+                    senders.push((connection_id, sender.clone()));
+                }
+            }
+        }
+
+        let msg = presence_msg(user_id, presence);
+        for (cid, tx) in senders {
+            self.send_or_disconnect_server_msg(cid, &tx, &msg);
+        }
+    }
+
+    // Tell a single connection where everyone it cares about currently stands
+    pub fn send_presence_snapshot(&mut self, client_id: u64, user_ids: HashSet<Uuid>) {
+        let Some(tx) = self.connections.get(&client_id).cloned() else {
+            return;
+        };
+        for user_id in user_ids {
+            let presence = match self.presence.get(&user_id) {
+                Some(p) => *p,
+                None => continue,
+            };
+            self.send_or_disconnect_server_msg(client_id, &tx, &presence_msg(user_id, presence));
+        }
     }
 
     pub fn send_or_disconnect_server_msg(
@@ -57,42 +354,505 @@ impl RouterState {
         }
     }
 
-    pub fn send_server_msg_to_conversation(
+    // Stores the message and fans it out, returns the id it was given
+    pub fn post_message(
+        &mut self,
+        conversation_id: Uuid,
+        from: Uuid,
+        message: String,
+        reply_to: Option<u64>,
+        attachment_ids: Vec<Uuid>,
+    ) -> Option<u64> {
+        let thread_root = reply_to
+            .and_then(|parent_id| self.find_message(parent_id))
+            .map(|parent| parent.thread_root.unwrap_or(parent.id));
+        let mentions = self.resolve_mentions(conversation_id, from, &message);
+
+        // From now on the attachments belong to this conversation
+        let mut attachments: Vec<Attachment> = vec![];
+        for attachment_id in attachment_ids {
+            if let Some(attachment) = self.attachments.get_mut(&attachment_id) {
+                attachment.conversation_id = Some(conversation_id);
+                attachments.push(attachment.clone());
+            }
+        }
+
+        let conversation = self.conversations.get_mut(&conversation_id)?;
+
+        let id = self.next_message_id;
+        self.next_message_id += 1;
+
+        let mut chat_message = ChatMessage::new(id, conversation_id, from, message);
+        chat_message.expires_at = conversation
+            .message_ttl
+            .and_then(|ttl| chrono::Duration::from_std(ttl).ok())
+            .map(|ttl| chat_message.sent_at + ttl);
+        chat_message.reply_to = reply_to;
+        chat_message.thread_root = thread_root;
+        chat_message.mentions = mentions.clone();
+        chat_message.attachments = attachments;
+        let view = chat_message.view(&self.names);
+        self.store.save_message(&chat_message);
+        conversation.messages.push(chat_message);
+        self.message_to_conversation.insert(id, conversation_id);
+
+        // Your own messages are read by definition
+        let receipt = conversation.receipts.entry(from).or_default();
+        receipt.delivered_up_to = id;
+        receipt.read_up_to = id;
+        self.store.write(Write::SaveReceipt {
+            conversation_id,
+            user_id: from,
+            receipt: *receipt,
+        });
+
+        self.send_server_msg_to_conversation(conversation_id, &ServerMsg::Chat(view));
+        self.notify_mentioned(conversation_id, id, from, &mentions);
+        Some(id)
+    }
+
+    // Only other participants can be mentioned, we don't want to ping strangers about a chat they can't see
+    pub fn resolve_mentions(&self, conversation_id: Uuid, from: Uuid, message: &str) -> Vec<Uuid> {
+        let Some(conversation) = self.conversations.get(&conversation_id) else {
+            return vec![];
+        };
+
+        let mut mentioned: Vec<Uuid> = vec![];
+        for mention in parse_mentions(message) {
+            let user_id = match mention {
+                Mention::Id(id) => id,
+                Mention::Username(username) => match self.directory.get(&username) {
+                    Some(id) => *id,
+                    None => continue,
+                },
+            };
+            if user_id != from
+                && conversation.participants.contains(&user_id)
+                && !mentioned.contains(&user_id)
+            {
+                mentioned.push(user_id);
+            }
+        }
+        mentioned
+    }
+
+    fn notify_mentioned(
+        &mut self,
+        conversation_id: Uuid,
+        message_id: u64,
+        from: Uuid,
+        mentioned: &[Uuid],
+    ) {
+        let msg = ServerMsg::Mentioned {
+            conversation: conversation_id.to_string(),
+            message_id,
+            from: from.to_string(),
+        };
+        for user_id in mentioned {
+            for connection_id in self.connections_for_user(user_id) {
+                if let Some(tx) = self.connections.get(&connection_id).cloned() {
+                    self.send_or_disconnect_server_msg(connection_id, &tx, &msg);
+                }
+            }
+        }
+    }
+
+    // Puts a persisted message back on startup, they have to come in id order
+    pub fn restore_message(&mut self, mut message: ChatMessage) {
+        let Some(conversation) = self.conversations.get_mut(&message.conversation_id) else {
+            return;
+        };
+        // Attachments are stored without who they belong to, the message already says
+        for attachment in &mut message.attachments {
+            attachment.owner = message.from;
+            attachment.conversation_id = Some(message.conversation_id);
+            self.attachments.insert(attachment.id, attachment.clone());
+        }
+        self.next_message_id = self.next_message_id.max(message.id + 1);
+        self.message_to_conversation
+            .insert(message.id, message.conversation_id);
+        conversation.messages.push(message);
+    }
+
+    pub fn find_message(&self, message_id: u64) -> Option<&ChatMessage> {
+        let conversation_id = self.message_to_conversation.get(&message_id)?;
+        let conversation = self.conversations.get(conversation_id)?;
+        conversation.messages.iter().find(|m| m.id == message_id)
+    }
+
+    pub fn find_message_mut(&mut self, message_id: u64) -> Option<&mut ChatMessage> {
+        let conversation_id = self.message_to_conversation.get(&message_id)?;
+        let conversation = self.conversations.get_mut(conversation_id)?;
+        conversation
+            .messages
+            .iter_mut()
+            .find(|m| m.id == message_id)
+    }
+
+    fn persist_message(&self, message_id: u64) {
+        if let Some(chat_message) = self.find_message(message_id) {
+            self.store.save_message(chat_message);
+        }
+    }
+
+    pub fn edit_message(&mut self, message_id: u64, message: String) {
+        let Some((conversation_id, from)) = self
+            .find_message(message_id)
+            .map(|m| (m.conversation_id, m.from))
+        else {
+            return;
+        };
+        let mentions = self.resolve_mentions(conversation_id, from, &message);
+
+        let Some(chat_message) = self.find_message_mut(message_id) else {
+            return;
+        };
+        chat_message.edit(message);
+
+        // Only ping people the edit newly mentions, the rest already heard about it
+        let newly_mentioned: Vec<Uuid> = mentions
+            .iter()
+            .filter(|u| !chat_message.mentions.contains(u))
+            .copied()
+            .collect();
+        chat_message.mentions = mentions;
+        let msg = ServerMsg::MessageEdited {
+            conversation: conversation_id.to_string(),
+            message_id,
+            message: chat_message.message.clone(),
+            edited_at: chat_message.edited_at.unwrap_or(chat_message.sent_at),
+        };
+        self.persist_message(message_id);
+        self.send_server_msg_to_conversation(conversation_id, &msg);
+        self.notify_mentioned(conversation_id, message_id, from, &newly_mentioned);
+    }
+
+    pub fn delete_message(&mut self, message_id: u64) {
+        let Some(chat_message) = self.find_message_mut(message_id) else {
+            return;
+        };
+        let attachment_ids: Vec<Uuid> = chat_message.attachments.iter().map(|a| a.id).collect();
+        chat_message.delete();
+        let conversation_id = chat_message.conversation_id;
+        let msg = ServerMsg::MessageDeleted {
+            conversation: conversation_id.to_string(),
+            message_id,
+            deleted_at: chat_message.deleted_at.unwrap_or(chat_message.sent_at),
+        };
+
+        // Nobody can download them anymore, the blobs may still be shared with other uploads
+        for attachment_id in attachment_ids {
+            self.attachments.remove(&attachment_id);
+        }
+        self.persist_message(message_id);
+        self.send_server_msg_to_conversation(conversation_id, &msg);
+    }
+
+    pub fn set_reaction(&mut self, message_id: u64, user_id: Uuid, emoji: String, reacted: bool) {
+        let Some(chat_message) = self.find_message_mut(message_id) else {
+            return;
+        };
+        if !chat_message.set_reaction(user_id, &emoji, reacted) {
+            return;
+        }
+        let conversation_id = chat_message.conversation_id;
+        let msg = ServerMsg::ReactionUpdated {
+            conversation: conversation_id.to_string(),
+            message_id,
+            count: chat_message.reaction_count(&emoji),
+            emoji,
+            user_id: user_id.to_string(),
+            reacted,
+        };
+        self.persist_message(message_id);
+        self.send_server_msg_to_conversation(conversation_id, &msg);
+    }
+
+    pub fn set_message_ttl(
+        &mut self,
+        conversation_id: Uuid,
+        changed_by: Uuid,
+        message_ttl: Option<Duration>,
+    ) {
+        let Some(conversation) = self.conversations.get_mut(&conversation_id) else {
+            return;
+        };
+        conversation.message_ttl = message_ttl;
+        self.store.save_conversation(conversation);
+        self.send_server_msg_to_conversation(
+            conversation_id,
+            &ServerMsg::MessageTtlChanged {
+                conversation: conversation_id.to_string(),
+                changed_by: changed_by.to_string(),
+                ttl_seconds: message_ttl.map(|t| t.as_secs()),
+            },
+        );
+    }
+
+    pub fn schedule_message(&mut self, scheduled: ScheduledMessage) {
+        self.store.write(Write::SaveScheduled(scheduled.clone()));
+        self.restore_scheduled(scheduled);
+    }
+
+    // Also used on startup for the ones that were already persisted
+    pub fn restore_scheduled(&mut self, scheduled: ScheduledMessage) {
+        if self
+            .scheduler
+            .send(ScheduleOp::Add {
+                id: scheduled.id,
+                send_at: scheduled.send_at,
+            })
+            .is_err()
+        {
+            eprintln!("scheduler has stopped, can't schedule {}", scheduled.id);
+        }
+        self.scheduled.insert(scheduled.id, scheduled);
+    }
+
+    pub fn cancel_scheduled(&mut self, schedule_id: Uuid) {
+        if self.take_scheduled(schedule_id).is_some() {
+            let _ = self.scheduler.send(ScheduleOp::Cancel { id: schedule_id });
+        }
+    }
+
+    // Removes it for good, whether it's about to be sent or was cancelled
+    pub fn take_scheduled(&mut self, schedule_id: Uuid) -> Option<ScheduledMessage> {
+        let scheduled = self.scheduled.remove(&schedule_id)?;
+        self.store.write(Write::DeleteScheduled { id: schedule_id });
+        Some(scheduled)
+    }
+
+    // Expired messages are gone entirely, not tombstoned like deletes
+    pub fn purge_expired_messages(&mut self, now: DateTime<Utc>) {
+        let mut expired: Vec<(Uuid, Vec<u64>)> = vec![];
+        for conversation in self.conversations.values_mut() {
+            let mut expired_ids: Vec<u64> = vec![];
+            conversation.messages.retain(|m| {
+                let keep = m.expires_at.is_none_or(|expires_at| expires_at > now);
+                if !keep {
+                    expired_ids.push(m.id);
+                    for attachment in &m.attachments {
+                        self.attachments.remove(&attachment.id);
+                    }
+                }
+                keep
+            });
+            if !expired_ids.is_empty() {
+                expired.push((conversation.id, expired_ids));
+            }
+        }
+
+        for (conversation_id, message_ids) in expired {
+            for message_id in &message_ids {
+                self.message_to_conversation.remove(message_id);
+            }
+            self.store.write(Write::DeleteMessages {
+                ids: message_ids.clone(),
+            });
+            self.send_server_msg_to_conversation(
+                conversation_id,
+                &ServerMsg::MessagesExpired {
+                    conversation: conversation_id.to_string(),
+                    message_ids,
+                },
+            );
+        }
+    }
+
+    pub fn send_server_msg_to_conversation(&mut self, conversation_id: Uuid, msg: &ServerMsg) {
+        self.broadcast_to_conversation(conversation_id, msg, None);
+    }
+
+    // Receipts only ever move forward, and reading something means it was delivered
+    pub fn update_receipt(
+        &mut self,
+        conversation_id: Uuid,
+        user_id: Uuid,
+        status: ReceiptStatus,
+        up_to_message_id: u64,
+    ) {
+        let Some(conversation) = self.conversations.get_mut(&conversation_id) else {
+            return;
+        };
+        let receipt = conversation.receipts.entry(user_id).or_default();
+        let previous = *receipt;
+        receipt.delivered_up_to = receipt.delivered_up_to.max(up_to_message_id);
+        if status == ReceiptStatus::Read {
+            receipt.read_up_to = receipt.read_up_to.max(up_to_message_id);
+        }
+
+        let advanced = match status {
+            ReceiptStatus::Delivered => receipt.delivered_up_to != previous.delivered_up_to,
+            ReceiptStatus::Read => receipt.read_up_to != previous.read_up_to,
+        };
+        if !advanced {
+            return;
+        }
+        self.store.write(Write::SaveReceipt {
+            conversation_id,
+            user_id,
+            receipt: *receipt,
+        });
+
+        // Everyone gets this, the senders for their ticks and the reader's other devices for unread counts
+        self.send_server_msg_to_conversation(
+            conversation_id,
+            &ServerMsg::Receipt {
+                conversation: conversation_id.to_string(),
+                user_id: user_id.to_string(),
+                status,
+                up_to_message_id,
+            },
+        );
+    }
+
+    // Sends to every connection of every participant, optionally leaving one user out
+    pub fn broadcast_to_conversation(
+        &mut self,
+        conversation_id: Uuid,
+        msg: &ServerMsg,
+        skip_user: Option<Uuid>,
+    ) {
+        let participants = match self.conversations.get(&conversation_id) {
+            Some(c) => c.participants.clone(),
+            None => return,
+        };
+
+        let mut senders: Vec<(u64, UnboundedSender<Message>)> = vec![];
+        for user_id in &participants {
+            if skip_user == Some(*user_id) {
+                continue;
+            }
+            for connection_id in self.connections_for_user(user_id) {
+                if let Some(sender) = self.connections.get(&connection_id) {
+                    senders.push((connection_id, sender.clone()));
+                }
+            }
+        }
+
+        for (cid, tx) in senders {
+            self.send_or_disconnect_server_msg(cid, &tx, msg);
+        }
+    }
+
+    pub fn start_typing(&mut self, conversation_id: Uuid, user_id: Uuid) {
+        let already_typing = self
+            .typing
+            .insert((conversation_id, user_id), Instant::now() + TYPING_TIMEOUT)
+            .is_some();
+
+        // Refreshes just push the expiry back, the others already know
+        if !already_typing {
+            self.broadcast_to_conversation(
+                conversation_id,
+                &ServerMsg::TypingStarted {
+                    conversation: conversation_id.to_string(),
+                    user_id: user_id.to_string(),
+                },
+                Some(user_id),
+            );
+        }
+    }
+
+    pub fn stop_typing(&mut self, conversation_id: Uuid, user_id: Uuid) {
+        if self.typing.remove(&(conversation_id, user_id)).is_none() {
+            return;
+        }
+        self.broadcast_to_conversation(
+            conversation_id,
+            &ServerMsg::TypingStopped {
+                conversation: conversation_id.to_string(),
+                user_id: user_id.to_string(),
+            },
+            Some(user_id),
+        );
+    }
+
+    fn stop_all_typing(&mut self, user_id: Uuid) {
+        let conversation_ids: Vec<Uuid> = self
+            .typing
+            .keys()
+            .filter(|(_, typing_user)| *typing_user == user_id)
+            .map(|(conversation_id, _)| *conversation_id)
+            .collect();
+        for conversation_id in conversation_ids {
+            self.stop_typing(conversation_id, user_id);
+        }
+    }
+
+    pub fn expire_typing(&mut self, now: Instant) {
+        let expired: Vec<(Uuid, Uuid)> = self
+            .typing
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(key, _)| *key)
+            .collect();
+        for (conversation_id, user_id) in expired {
+            self.stop_typing(conversation_id, user_id);
+        }
+    }
+
+    pub fn send_envelopes_to_devices(
         &mut self,
         conversation_id: Uuid,
         sender_connection_id: u64,
-        message: &str,
+        ciphertexts: HashMap<i64, String>,
     ) {
-        // Get the original sender ID
         let original_sender_id = match self.connection_to_user.get(&sender_connection_id) {
             Some(s) => *s,
             None => return,
         };
+        let original_sender_device = match self.connection_to_device.get(&sender_connection_id) {
+            Some(d) => *d,
+            None => return,
+        };
 
         let participants = match self.conversations.get(&conversation_id) {
             Some(c) => c.participants.clone(),
             None => return,
         };
 
-        let mut senders: Vec<(u64, UnboundedSender<Message>)> = vec![];
-        for (connection_id, user_id) in &self.connection_to_user {
-            if participants.contains(user_id) {
-                if let Some(sender) = self.connections.get(connection_id) {
-                    senders.push((*connection_id, sender.clone()));
+        // A device only ever gets the ciphertext that was encrypted for it
+        // Devices that aren't connected right now just miss it, there's no offline queue yet
+        let mut deliveries: Vec<(u64, UnboundedSender<Message>, String)> = vec![];
+        for user_id in &participants {
+            let Some(devices) = self.user_sessions.get(user_id) else {
+                continue;
+            };
+            for (device_id, connection_ids) in devices {
+                let Some(ciphertext) = device_id.and_then(|d| ciphertexts.get(&d)) else {
+                    continue;
+                };
+                for connection_id in connection_ids {
+                    if *connection_id == sender_connection_id {
+                        continue;
+                    }
+                    if let Some(sender) = self.connections.get(connection_id) {
+                        deliveries.push((*connection_id, sender.clone(), ciphertext.clone()));
+                    }
                 }
             }
         }
 
-        for (cid, tx) in senders {
+        for (cid, tx, ciphertext) in deliveries {
             self.send_or_disconnect_server_msg(
                 cid,
                 &tx,
-                &ServerMsg::Chat {
+                &ServerMsg::Envelope {
                     conversation: conversation_id.to_string(),
                     from: original_sender_id.to_string(),
-                    message: message.to_string(),
+                    from_device: original_sender_device,
+                    ciphertext,
                 },
             );
         }
     }
 }
+
+fn presence_msg(user_id: Uuid, presence: Presence) -> ServerMsg {
+    ServerMsg::PresenceChanged {
+        user_id: user_id.to_string(),
+        status: presence.status,
+        last_seen: presence.last_seen,
+    }
+}
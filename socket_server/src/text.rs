@@ -0,0 +1,227 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_properties::{
+    GeneralCategory, UnicodeEmoji, UnicodeGeneralCategory,
+    emoji::{is_tag_character, is_zwj},
+};
+use unicode_segmentation::UnicodeSegmentation;
+
+pub const DEFAULT_MAX_MESSAGE_GRAPHEMES: usize = 256;
+
+// Graphemes can be stacked with combining marks indefinitely, so we still want some byte
+// ceiling to stop one "character" being megabytes long
+const MAX_BYTES_PER_GRAPHEME: usize = 32;
+
+pub enum TextError {
+    Empty,
+    TooLong,
+    DisallowedCharacter,
+}
+
+fn is_format(c: char) -> bool {
+    c.general_category() == GeneralCategory::Format
+}
+
+// Invisible formatting (bidi controls, zero width spaces and marks...) can make a message render
+// differently to what it says, same rule as auth_service has for display names
+// ZWJ and tag characters are only let through because emoji sequences and subdivision flags need them
+fn is_disallowed_format(c: char) -> bool {
+    is_format(c) && !is_zwj(c) && !is_tag_character(c)
+}
+
+// Everything that ends up in a message body goes through here, returns the text to store
+pub fn normalize_message(message: &str, max_graphemes: usize) -> Result<String, TextError> {
+    if message.chars().any(is_disallowed_format) {
+        return Err(TextError::DisallowedCharacter);
+    }
+
+    // Control characters are just noise (or terminal escapes), newlines and tabs are fine
+    let normalized: String = message
+        .nfc()
+        .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+        .collect();
+
+    // A lone ZWJ or tag is still nothing to look at
+    if normalized
+        .chars()
+        .all(|c| c.is_whitespace() || is_format(c))
+    {
+        return Err(TextError::Empty);
+    }
+
+    if normalized.len() > max_graphemes * MAX_BYTES_PER_GRAPHEME
+        || normalized.graphemes(true).count() > max_graphemes
+    {
+        return Err(TextError::TooLong);
+    }
+
+    Ok(normalized)
+}
+
+// Digits, # and * are only emoji as keycaps, like 1️⃣
+fn is_keycap(emoji: &str) -> bool {
+    let mut chars = emoji.chars();
+    matches!(chars.next(), Some('0'..='9' | '#' | '*'))
+        && matches!(
+            (chars.next(), chars.next(), chars.next()),
+            (Some('\u{20E3}'), None, None) | (Some('\u{FE0F}'), Some('\u{20E3}'), None)
+        )
+}
+
+// A reaction is exactly one emoji as the user sees it, however many code points that takes
+// Anything else in the grapheme (skin tones, ZWJ, VS16, flag and tag characters) has to be emoji too
+pub fn is_valid_emoji(emoji: &str) -> bool {
+    if emoji.len() > MAX_BYTES_PER_GRAPHEME || emoji.graphemes(true).count() != 1 {
+        return false;
+    }
+    if is_keycap(emoji) {
+        return true;
+    }
+    let mut chars = emoji.chars();
+    chars
+        .next()
+        .is_some_and(|base| !base.is_ascii() && base.is_emoji_char())
+        && chars.all(|c| c.is_emoji_char_or_emoji_component())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: usize = 10;
+
+    #[test]
+    fn normalize_composes_to_nfc() {
+        let decomposed = "cafe\u{301}";
+        assert_eq!(
+            normalize_message(decomposed, MAX).ok().as_deref(),
+            Some("café")
+        );
+    }
+
+    #[test]
+    fn normalize_strips_control_characters_but_keeps_newlines_and_tabs() {
+        let message = "a\u{1b}[31mb\u{7}\n\tc";
+        assert_eq!(
+            normalize_message(message, MAX).ok().as_deref(),
+            Some("a[31mb\n\tc")
+        );
+    }
+
+    #[test]
+    fn normalize_rejects_empty_and_whitespace() {
+        for message in ["", "   ", "\n\t", "\u{7}\u{1b}"] {
+            assert!(
+                matches!(normalize_message(message, MAX), Err(TextError::Empty)),
+                "{message:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_rejects_invisible_formatting() {
+        for message in [
+            "evil\u{202E}txt.exe",
+            "a\u{2066}b",
+            "left\u{200E}",
+            "right\u{200F}",
+            "arabic\u{061C}mark",
+            "zero\u{200B}width",
+            "\u{200B}\u{200B}\u{200B}",
+            "word\u{2060}joiner",
+            "\u{FEFF}bom",
+            "soft\u{AD}hyphen",
+        ] {
+            assert!(
+                matches!(
+                    normalize_message(message, MAX),
+                    Err(TextError::DisallowedCharacter)
+                ),
+                "{message:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_keeps_emoji_sequences() {
+        for message in ["👨‍👩‍👧‍👦", "🏳️‍🌈 pride", "🏴󠁧󠁢󠁳󠁣󠁴󠁿", "1️⃣ first"]
+        {
+            assert_eq!(
+                normalize_message(message, MAX).ok().as_deref(),
+                Some(message)
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_rejects_sequence_characters_on_their_own() {
+        for message in ["\u{200D}", " \u{200D} ", "\u{E0067}\u{E007F}"] {
+            assert!(
+                matches!(normalize_message(message, MAX), Err(TextError::Empty)),
+                "{message:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn normalize_counts_graphemes_not_code_points() {
+        // One family emoji is seven code points but a single grapheme
+        let families = "👨‍👩‍👧‍👦".repeat(MAX);
+        assert!(normalize_message(&families, MAX).is_ok());
+        let too_many = "a".repeat(MAX + 1);
+        assert!(matches!(
+            normalize_message(&too_many, MAX),
+            Err(TextError::TooLong)
+        ));
+    }
+
+    #[test]
+    fn normalize_caps_bytes_for_stacked_combining_marks() {
+        let zalgo = format!("a{}", "\u{301}".repeat(MAX * MAX_BYTES_PER_GRAPHEME));
+        assert!(matches!(
+            normalize_message(&zalgo, MAX),
+            Err(TextError::TooLong)
+        ));
+    }
+
+    #[test]
+    fn emoji_accepts_single_emoji_of_any_length() {
+        for emoji in [
+            "👍",
+            "❤",
+            "❤️",
+            "👍🏽",
+            "👨‍👩‍👧‍👦",
+            "🏳️‍🌈",
+            "🇬🇧",
+            "🏴󠁧󠁢󠁳󠁣󠁴󠁿",
+            "1️⃣",
+            "#️⃣",
+            "*⃣",
+        ] {
+            assert!(is_valid_emoji(emoji), "{emoji:?}");
+        }
+    }
+
+    #[test]
+    fn emoji_rejects_text_and_non_emoji_symbols() {
+        for text in [
+            "",
+            "a",
+            "1",
+            "#",
+            "ab",
+            "€",
+            "→",
+            "。",
+            "é",
+            "中",
+            "👍👍",
+            "👍 ",
+            "1\u{FE0F}",
+            "a\u{20E3}",
+            "👍a",
+        ] {
+            assert!(!is_valid_emoji(text), "{text:?}");
+        }
+    }
+}